@@ -0,0 +1,10 @@
+CREATE TABLE video_analytics (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    bc_video_id TEXT not null,
+    dimension VARCHAR(20) not null,
+    value TEXT not null,
+    video_view INTEGER default 0,
+
+    UNIQUE (bc_video_id, dimension, value)
+)
@@ -0,0 +1,6 @@
+CREATE TABLE sync_state (
+    name TEXT PRIMARY KEY,
+
+    -- unix time in milliseconds, same unit as the Analytics from/to parameters
+    synced_at INTEGER not null
+)
@@ -0,0 +1,5 @@
+ALTER TABLE videos ADD COLUMN poster TEXT;
+ALTER TABLE videos ADD COLUMN duration INTEGER;
+-- json arrays of the Playback API sources and text_tracks
+ALTER TABLE videos ADD COLUMN sources TEXT;
+ALTER TABLE videos ADD COLUMN text_tracks TEXT;
@@ -0,0 +1,15 @@
+CREATE TABLE playlists (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    name TEXT not null,
+    description TEXT,
+    bc_playlist_id TEXT UNIQUE not null
+);
+
+CREATE TABLE playlist_videos (
+    bc_playlist_id TEXT not null,
+    bc_video_id TEXT not null,
+    position INTEGER not null,
+
+    PRIMARY KEY (bc_playlist_id, position)
+);
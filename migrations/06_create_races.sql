@@ -0,0 +1,33 @@
+CREATE TABLE races (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    data TEXT not null,
+    ippodromo VARCHAR(50) not null,
+    numero_corsa VARCHAR(5) not null,
+    tipologia TEXT,
+    cavalli TEXT,
+    fantini TEXT,
+    primo VARCHAR(50),
+    secondo VARCHAR(50),
+    terzo VARCHAR(50),
+
+    UNIQUE (data, ippodromo, numero_corsa)
+);
+
+ALTER TABLE videos ADD COLUMN race_id INTEGER REFERENCES races (id);
+
+CREATE INDEX videos_race_id ON videos (race_id);
+
+INSERT INTO races (data, ippodromo, numero_corsa, tipologia, cavalli, fantini, primo, secondo, terzo)
+SELECT data, ippodromo, numero_corsa, MAX(tipologia), MAX(cavalli), MAX(fantini), MAX(primo), MAX(secondo), MAX(terzo)
+FROM videos
+WHERE ippodromo IS NOT NULL AND numero_corsa IS NOT NULL
+GROUP BY data, ippodromo, numero_corsa;
+
+UPDATE videos SET race_id = (
+    SELECT races.id
+    FROM races
+    WHERE races.data = videos.data
+    AND races.ippodromo = videos.ippodromo
+    AND races.numero_corsa = videos.numero_corsa
+);
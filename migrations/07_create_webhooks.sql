@@ -0,0 +1,28 @@
+CREATE TABLE webhook_subscriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    url TEXT not null,
+    secret TEXT not null,
+    -- comma separated: video.created,video.updated,video.deleted,views.updated
+    events TEXT not null,
+    active BOOLEAN not null default 1,
+    created_at INTEGER not null
+);
+
+-- the retry queue and the delivery log: pending rows are retried until delivered or failed
+CREATE TABLE webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    subscription_id INTEGER not null REFERENCES webhook_subscriptions (id),
+    event VARCHAR(20) not null,
+    payload TEXT not null,
+    status VARCHAR(10) not null default 'pending',
+    attempts INTEGER not null default 0,
+    next_attempt_at INTEGER not null,
+    last_status_code INTEGER,
+    last_error TEXT,
+    created_at INTEGER not null,
+    delivered_at INTEGER
+);
+
+CREATE INDEX webhook_deliveries_pending ON webhook_deliveries (status, next_attempt_at);
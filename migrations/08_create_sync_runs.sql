@@ -0,0 +1,16 @@
+-- one row per pass of the video and views syncs, the last ones are listed by /admin/sync-runs
+CREATE TABLE sync_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    -- sync_videos, resync_videos or sync_views
+    task TEXT not null,
+    started_at INTEGER not null,
+    -- null while running, or if the process died during the run
+    finished_at INTEGER,
+    videos_fetched INTEGER not null default 0,
+    videos_inserted INTEGER not null default 0,
+    videos_updated INTEGER not null default 0,
+    videos_deleted INTEGER not null default 0,
+    videos_failed INTEGER not null default 0,
+    error TEXT
+);
@@ -0,0 +1,15 @@
+-- keys of the API clients, only the sha256 of the key is kept
+CREATE TABLE api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+
+    name TEXT not null,
+    -- first characters of the key, to tell the keys apart
+    prefix TEXT not null,
+    key_hash TEXT not null UNIQUE,
+    -- comma separated: video_views
+    scopes TEXT not null default '',
+    -- requests per minute
+    rate_limit INTEGER not null,
+    created_at INTEGER not null,
+    revoked_at INTEGER
+);
@@ -0,0 +1,4 @@
+-- set when Brightcove stops playing the video: deactivated, out of its schedule or deleted.
+-- the video is hidden from the API but keeps its views, analytics and playlist links, and
+-- comes back as it was if Brightcove plays it again
+ALTER TABLE videos ADD COLUMN unavailable_at INTEGER;
@@ -0,0 +1,47 @@
+-- races are keyed by numero_corsa without its zero padding ("02" is race "2"), as the
+-- racedays group them. merges the races the raw key split, keeping the oldest row
+CREATE TEMP TABLE race_keys AS
+SELECT
+    id,
+    data,
+    ippodromo,
+    CASE
+        WHEN trim(numero_corsa) <> '' AND trim(numero_corsa) NOT GLOB '*[^0-9]*'
+        THEN CAST(CAST(trim(numero_corsa) AS INTEGER) AS TEXT)
+        ELSE trim(numero_corsa)
+    END AS numero_corsa
+FROM races;
+
+CREATE TEMP TABLE race_merges AS
+SELECT
+    id,
+    (
+        SELECT MIN(same.id)
+        FROM race_keys same
+        WHERE same.data = race_keys.data
+        AND same.ippodromo = race_keys.ippodromo
+        AND same.numero_corsa = race_keys.numero_corsa
+    ) AS into_id
+FROM race_keys;
+
+UPDATE races SET
+    tipologia = COALESCE(tipologia, (SELECT MAX(r.tipologia) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id)),
+    cavalli = COALESCE(cavalli, (SELECT MAX(r.cavalli) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id)),
+    fantini = COALESCE(fantini, (SELECT MAX(r.fantini) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id)),
+    primo = COALESCE(primo, (SELECT MAX(r.primo) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id)),
+    secondo = COALESCE(secondo, (SELECT MAX(r.secondo) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id)),
+    terzo = COALESCE(terzo, (SELECT MAX(r.terzo) FROM race_merges m JOIN races r ON r.id = m.id WHERE m.into_id = races.id))
+WHERE id IN (SELECT into_id FROM race_merges WHERE id <> into_id);
+
+UPDATE videos SET race_id = (SELECT into_id FROM race_merges WHERE race_merges.id = videos.race_id)
+WHERE race_id IS NOT NULL;
+
+DELETE FROM races WHERE id IN (SELECT id FROM race_merges WHERE id <> into_id);
+
+UPDATE races SET numero_corsa = (SELECT numero_corsa FROM race_keys WHERE race_keys.id = races.id);
+
+-- the races left behind by videos that moved to another race
+DELETE FROM races WHERE id NOT IN (SELECT race_id FROM videos WHERE race_id IS NOT NULL);
+
+DROP TABLE race_merges;
+DROP TABLE race_keys;
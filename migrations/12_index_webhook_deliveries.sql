@@ -0,0 +1,3 @@
+-- the delivery log is pruned by age, and listed per subscription by the admin routes
+CREATE INDEX webhook_deliveries_created_at ON webhook_deliveries (created_at);
+CREATE INDEX webhook_deliveries_subscription ON webhook_deliveries (subscription_id, id);
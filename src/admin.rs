@@ -0,0 +1,443 @@
+use axum::{
+    extract::{Extension, Path, Query},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+    Router,
+};
+use http::{header, HeaderMap, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use crate::api_keys::{self, ApiKeys, Scope};
+use crate::cache::{CacheStats, VideoCache};
+use crate::db;
+use crate::subscriptions::{self, Event};
+use crate::supervisor::{Supervisor, TaskState};
+use crate::writer::Writer;
+
+const DEFAULT_SYNC_RUNS: u32 = 20;
+const MAX_SYNC_RUNS: u32 = 200;
+const DEFAULT_WEBHOOK_DELIVERIES: u32 = 50;
+const MAX_WEBHOOK_DELIVERIES: u32 = 500;
+
+/// Wakes the background tasks up before their interval.
+#[derive(Clone, Default)]
+pub struct Triggers {
+    /// shared with the Brightcove notifications
+    pub video_sync: Arc<Notify>,
+    pub views_sync: Arc<Notify>,
+    pub token_refresh: Arc<Notify>,
+    /// the next video sync goes through the whole catalogue instead of the new videos
+    pub full_resync: Arc<AtomicBool>,
+}
+
+impl Triggers {
+    pub fn new() -> Self {
+        Triggers::default()
+    }
+}
+
+#[derive(Clone)]
+struct AdminConfig {
+    token: Option<Arc<String>>,
+    triggers: Triggers,
+    supervisor: Supervisor,
+    pool: SqlitePool,
+    writer: Writer,
+    api_keys: Arc<ApiKeys>,
+    video_cache: Arc<VideoCache>,
+}
+
+/// Routes operating the proxy. Requests need `Authorization: Bearer <ADMIN_TOKEN>`,
+/// without `ADMIN_TOKEN` set every request is refused.
+pub fn router(
+    triggers: Triggers,
+    supervisor: Supervisor,
+    pool: SqlitePool,
+    writer: Writer,
+    api_keys: Arc<ApiKeys>,
+    video_cache: Arc<VideoCache>,
+) -> Router {
+    let token = std::env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(Arc::new);
+
+    if token.is_none() {
+        log::info!("ADMIN_TOKEN not set, admin routes disabled");
+    }
+
+    Router::new()
+        .route("/sync/videos", post(sync_videos))
+        .route("/sync/views", post(sync_views))
+        .route("/sync/full", post(sync_full))
+        .route("/token/refresh", post(token_refresh))
+        .route("/sync-runs", get(sync_runs_index))
+        .route("/stats/cache", get(cache_stats))
+        .route("/stats/tasks", get(task_stats))
+        .route("/tasks/:name/pause", post(task_pause))
+        .route("/tasks/:name/resume", post(task_resume))
+        .route("/api-keys", get(api_keys_index).post(api_key_create))
+        .route("/api-keys/:id", delete(api_key_revoke))
+        .route("/webhooks", get(webhooks_index).post(webhook_create))
+        .route("/webhooks/:id", delete(webhook_deactivate))
+        .route("/webhooks/:id/deliveries", get(webhook_deliveries_index))
+        .layer(axum::middleware::from_fn(require_token))
+        .layer(Extension(AdminConfig {
+            token,
+            triggers,
+            supervisor,
+            pool,
+            writer,
+            api_keys,
+            video_cache,
+        }))
+}
+
+/// Lets through the requests with the `ADMIN_TOKEN`, for every admin route.
+async fn require_token<B>(req: Request<B>, next: Next<B>) -> Response {
+    let authorized = match req.extensions().get::<AdminConfig>() {
+        Some(config) => authorize(&config.token, req.headers()),
+        None => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match authorized {
+        Ok(()) => next.run(req).await,
+        Err(status_code) => status_code.into_response(),
+    }
+}
+
+fn authorize(token: &Option<Arc<String>>, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = match token {
+        Some(token) => token,
+        None => return Err(StatusCode::NOT_IMPLEMENTED),
+    };
+
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match given {
+        Some(given)
+            if given.len() == token.len()
+                && openssl::memcmp::eq(given.as_bytes(), token.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn is_paused(config: &AdminConfig, task: &str) -> bool {
+    config
+        .supervisor
+        .states()
+        .get(task)
+        .map(|state| state.paused)
+        .unwrap_or(false)
+}
+
+/// Wakes `task` up, `409 Conflict` if it's paused.
+fn trigger(config: &AdminConfig, task: &str, notify: &Notify) -> StatusCode {
+    if is_paused(config, task) {
+        return StatusCode::CONFLICT;
+    }
+
+    log::info!(target: "admin", "{} triggered", task);
+    notify.notify_one();
+
+    StatusCode::ACCEPTED
+}
+
+async fn sync_videos(config: Extension<AdminConfig>) -> StatusCode {
+    trigger(&config, "sync_videos", &config.triggers.video_sync)
+}
+
+async fn sync_views(config: Extension<AdminConfig>) -> StatusCode {
+    trigger(&config, "sync_views", &config.triggers.views_sync)
+}
+
+/// Runs the video sync over the whole catalogue: new videos are inserted, the others updated
+/// and the ones Brightcove doesn't play anymore made unavailable. A full views sync follows.
+async fn sync_full(config: Extension<AdminConfig>) -> StatusCode {
+    if is_paused(&config, "sync_videos") {
+        return StatusCode::CONFLICT;
+    }
+
+    config.triggers.full_resync.store(true, Ordering::SeqCst);
+    trigger(&config, "sync_videos", &config.triggers.video_sync)
+}
+
+async fn token_refresh(config: Extension<AdminConfig>) -> StatusCode {
+    trigger(&config, "access_token", &config.triggers.token_refresh)
+}
+
+async fn cache_stats(config: Extension<AdminConfig>) -> Json<CacheStats> {
+    Json(config.video_cache.stats())
+}
+
+async fn task_stats(config: Extension<AdminConfig>) -> Json<BTreeMap<&'static str, TaskState>> {
+    Json(config.supervisor.states())
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncRunsParams {
+    limit: Option<u32>,
+}
+
+/// `?limit=N` (20 by default, up to 200) last runs of the video and views syncs.
+async fn sync_runs_index(
+    config: Extension<AdminConfig>,
+    params: Query<SyncRunsParams>,
+) -> Result<Json<Vec<db::SyncRun>>, StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_SYNC_RUNS).min(MAX_SYNC_RUNS);
+
+    db::get_sync_runs(&config.pool, limit)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!(target: "admin", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn task_pause(config: Extension<AdminConfig>, name: Path<String>) -> StatusCode {
+    set_paused(&config, &name, true)
+}
+
+async fn task_resume(config: Extension<AdminConfig>, name: Path<String>) -> StatusCode {
+    set_paused(&config, &name, false)
+}
+
+fn set_paused(config: &AdminConfig, name: &str, paused: bool) -> StatusCode {
+    match config.supervisor.set_paused(name, paused) {
+        true => {
+            log::info!(target: "admin", "{} {}", name, if paused { "paused" } else { "resumed" });
+            StatusCode::NO_CONTENT
+        }
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyParams {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    /// requests per minute, `API_KEY_RATE_LIMIT` by default
+    rate_limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiKeyCreated {
+    #[serde(flatten)]
+    api_key: db::ApiKeyRow,
+    /// shown once, only its hash is kept
+    key: String,
+}
+
+async fn api_keys_index(
+    config: Extension<AdminConfig>,
+) -> Result<Json<Vec<db::ApiKeyRow>>, StatusCode> {
+    db::get_api_keys(&config.pool).await.map(Json).map_err(|e| {
+        log::error!(target: "admin", "{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// `{"name": "site", "scopes": ["video_views"], "rate_limit": 120}`, `422` for an unknown
+/// scope.
+async fn api_key_create(
+    config: Extension<AdminConfig>,
+    params: Json<ApiKeyParams>,
+) -> Result<(StatusCode, Json<ApiKeyCreated>), StatusCode> {
+    let scopes = params
+        .scopes
+        .iter()
+        .map(|scope| Scope::parse(scope).ok_or(StatusCode::UNPROCESSABLE_ENTITY))
+        .collect::<Result<Vec<_>, _>>()?;
+    let rate_limit = params
+        .rate_limit
+        .unwrap_or(config.api_keys.default_rate_limit);
+
+    match api_keys::create(&config.writer, params.0.name, &scopes, rate_limit).await {
+        Ok((api_key, key)) => {
+            log::info!(target: "admin", "api key {} created", api_key.id);
+            Ok((StatusCode::CREATED, Json(ApiKeyCreated { api_key, key })))
+        }
+        Err(e) => {
+            log::error!(target: "admin", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn api_key_revoke(config: Extension<AdminConfig>, id: Path<i64>) -> StatusCode {
+    let id = id.0;
+    let now = crate::unix_time_in_ms();
+    let revoked = config
+        .writer
+        .run(move |conn| Box::pin(db::revoke_api_key(conn, id, now)))
+        .await;
+
+    match revoked {
+        Ok(true) => {
+            config.api_keys.invalidate();
+            log::info!(target: "admin", "api key {} revoked", id);
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            log::error!(target: "admin", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookParams {
+    url: String,
+    events: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookCreated {
+    #[serde(flatten)]
+    subscription: db::WebhookSubscriptionRow,
+    /// shown once, the deliveries are signed with it
+    secret: String,
+}
+
+async fn webhooks_index(
+    config: Extension<AdminConfig>,
+) -> Result<Json<Vec<db::WebhookSubscriptionRow>>, StatusCode> {
+    db::get_webhook_subscriptions(&config.pool)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!(target: "admin", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// `{"url": "https://example.com/hook", "events": ["video.created"]}`, `422` for an unknown
+/// event or a url that isn't http(s).
+async fn webhook_create(
+    config: Extension<AdminConfig>,
+    params: Json<WebhookParams>,
+) -> Result<(StatusCode, Json<WebhookCreated>), StatusCode> {
+    let events = params
+        .events
+        .iter()
+        .map(|event| Event::parse(event).ok_or(StatusCode::UNPROCESSABLE_ENTITY))
+        .collect::<Result<Vec<_>, _>>()?;
+    let url_valid = reqwest::Url::parse(&params.url)
+        .map(|url| matches!(url.scheme(), "http" | "https"))
+        .unwrap_or(false);
+    if events.is_empty() || !url_valid {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    match subscriptions::subscribe(&config.writer, params.0.url, &events).await {
+        Ok((subscription, secret)) => {
+            log::info!(target: "admin", "webhook subscription {} created", subscription.id);
+            Ok((
+                StatusCode::CREATED,
+                Json(WebhookCreated {
+                    subscription,
+                    secret,
+                }),
+            ))
+        }
+        Err(e) => {
+            log::error!(target: "admin", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookDeliveriesParams {
+    status: Option<String>,
+    limit: Option<u32>,
+}
+
+/// `?status=failed&limit=N` (50 by default, up to 500) last deliveries of a subscription,
+/// `400` for a status other than `pending`, `delivered` or `failed`.
+async fn webhook_deliveries_index(
+    config: Extension<AdminConfig>,
+    id: Path<i64>,
+    params: Query<WebhookDeliveriesParams>,
+) -> Result<Json<Vec<db::WebhookDeliveryRow>>, StatusCode> {
+    let status = params.status.as_deref();
+    if !matches!(status, None | Some("pending" | "delivered" | "failed")) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_WEBHOOK_DELIVERIES)
+        .min(MAX_WEBHOOK_DELIVERIES);
+
+    db::get_webhook_deliveries(&config.pool, id.0, status, limit)
+        .await
+        .map(Json)
+        .map_err(|e| {
+            log::error!(target: "admin", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Stops the deliveries to the subscription, the pending ones are dropped.
+async fn webhook_deactivate(config: Extension<AdminConfig>, id: Path<i64>) -> StatusCode {
+    let id = id.0;
+    let deactivated = config
+        .writer
+        .run(move |conn| Box::pin(db::deactivate_webhook_subscription(conn, id)))
+        .await;
+
+    match deactivated {
+        Ok(true) => {
+            log::info!(target: "admin", "webhook subscription {} deactivated", id);
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            log::error!(target: "admin", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[test]
+fn authorize_bearer_token() {
+    let token = Some(Arc::new("s3cret".to_string()));
+    let headers = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    };
+
+    assert_eq!(authorize(&token, &headers("Bearer s3cret")), Ok(()));
+    assert_eq!(
+        authorize(&token, &headers("Bearer s3cre")),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        authorize(&token, &headers("Basic s3cret")),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        authorize(&token, &HeaderMap::new()),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        authorize(&None, &headers("Bearer s3cret")),
+        Err(StatusCode::NOT_IMPLEMENTED)
+    );
+}
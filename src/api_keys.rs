@@ -0,0 +1,609 @@
+//! API keys of the `/api/v1` clients: who they are, what they can see and how often they can
+//! ask.
+//!
+//! A key is sent as `X-API-Key: <key>` or `Authorization: Bearer <key>`. Only its sha256 is
+//! stored. Each key has a token bucket of `rate_limit` requests, refilled over a minute, and
+//! scopes: without `video_views` the view counts are left out of the responses, without
+//! `playback_token` no Playback Rights token is signed.
+
+use axum::{
+    extract::ConnectInfo,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::brightcove::Video;
+use crate::db;
+use crate::writer::Writer;
+
+const DEFAULT_RATE_LIMIT: u32 = 60;
+/// How long a key looked up stays in memory, a revoked key may be accepted for as long.
+const KEY_TTL: Duration = Duration::from_secs(60);
+/// Most keys kept in memory, past it the oldest looked up is dropped.
+const MAX_CACHED_KEYS: usize = 1000;
+/// Unknown keys a client IP can try per minute, past it its unknown keys get `429`.
+const FAILED_LOOKUPS_PER_MINUTE: u32 = 10;
+/// Most client IPs whose failed lookups are counted, past it the ones back to a full bucket
+/// are dropped.
+const MAX_FAILING_CLIENTS: usize = 10_000;
+const KEY_PREFIX: &str = "bcp_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// the view counts of the videos, and the analytics routes
+    VideoViews,
+    /// Playback Rights tokens, for keys only
+    PlaybackToken,
+    /// `live=true` on `/api/v1/analytics`, which calls Analytics
+    AnalyticsLive,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [
+        Scope::VideoViews,
+        Scope::PlaybackToken,
+        Scope::AnalyticsLive,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::VideoViews => "video_views",
+            Scope::PlaybackToken => "playback_token",
+            Scope::AnalyticsLive => "analytics_live",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|s| s.as_str() == scope)
+    }
+}
+
+/// What the client of a request can do, for the handlers to read from the extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Access {
+    /// `None` for the requests without a key, allowed unless `API_KEYS_REQUIRED=true`
+    pub key_id: Option<i64>,
+    scopes: Vec<Scope>,
+}
+
+impl Access {
+    /// Requests without a key, and the routes outside `/api/v1`, with the
+    /// `API_KEYS_ANONYMOUS_SCOPES`.
+    pub fn anonymous(scopes: &[Scope]) -> Self {
+        Access {
+            key_id: None,
+            scopes: scopes.to_vec(),
+        }
+    }
+
+    fn key(key: &db::ApiKeyRow) -> Self {
+        Access {
+            key_id: Some(key.id),
+            scopes: key.scopes.split(',').filter_map(Scope::parse).collect(),
+        }
+    }
+
+    pub fn can(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// `403 Forbidden` without `scope`.
+    pub fn require(&self, scope: Scope) -> Result<(), StatusCode> {
+        match self.can(scope) {
+            true => Ok(()),
+            false => Err(StatusCode::FORBIDDEN),
+        }
+    }
+
+    /// Leaves the view counts out of `videos` without the `video_views` scope.
+    pub fn redact<'a>(&self, videos: impl IntoIterator<Item = &'a mut Video>) {
+        if !self.can(Scope::VideoViews) {
+            for video in videos {
+                video.video_views = None;
+            }
+        }
+    }
+}
+
+/// Token bucket of a key, refilled at `capacity` per minute.
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Quota {
+    limit: u32,
+    remaining: u32,
+    /// seconds until the bucket is full again
+    reset: u64,
+}
+
+impl Bucket {
+    fn new(capacity: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: capacity as f64,
+            updated: now,
+        }
+    }
+
+    /// Whether there's a token, without taking it. `Err` with the seconds until there's one if
+    /// the bucket is empty.
+    fn check(&mut self, capacity: u32, now: Instant) -> Result<(), u64> {
+        let capacity = capacity.max(1) as f64;
+        let per_second = capacity / 60.0;
+
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+
+        match self.tokens < 1.0 {
+            true => Err(((1.0 - self.tokens) / per_second).ceil() as u64),
+            false => Ok(()),
+        }
+    }
+
+    fn is_full(&self, capacity: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * capacity.max(1) as f64 / 60.0 >= capacity as f64
+    }
+
+    /// Takes a token. `Err` with the seconds until there's one if the bucket is empty.
+    fn take(&mut self, capacity: u32, now: Instant) -> Result<Quota, u64> {
+        self.check(capacity, now)?;
+
+        let capacity = capacity.max(1) as f64;
+        let per_second = capacity / 60.0;
+        self.tokens -= 1.0;
+
+        Ok(Quota {
+            limit: capacity as u32,
+            remaining: self.tokens.floor() as u32,
+            reset: ((capacity - self.tokens) / per_second).ceil() as u64,
+        })
+    }
+}
+
+/// The keys and their buckets, checked by `authenticate` in front of `/api/v1`.
+pub struct ApiKeys {
+    pool: SqlitePool,
+    /// `API_KEYS_REQUIRED=true` refuses the requests without a key
+    required: bool,
+    /// requests per minute of the keys created without a limit, `API_KEY_RATE_LIMIT`
+    pub default_rate_limit: u32,
+    /// `API_KEYS_ANONYMOUS_SCOPES`, none by default
+    anonymous_scopes: Vec<Scope>,
+    /// `TRUST_X_FORWARDED_FOR=true` behind a load balancer, the client IP is the last address
+    /// of `X-Forwarded-For` instead of the peer
+    trust_forwarded_for: bool,
+    /// the keys found, by hash, unknown ones aren't kept
+    keys: Mutex<HashMap<String, (db::ApiKeyRow, Instant)>>,
+    buckets: Mutex<HashMap<i64, Bucket>>,
+    /// failed lookups by client IP
+    failures: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl ApiKeys {
+    pub fn from_env(pool: SqlitePool) -> anyhow::Result<Self> {
+        let required = std::env::var("API_KEYS_REQUIRED")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+        let default_rate_limit = crate::env_var("API_KEY_RATE_LIMIT", DEFAULT_RATE_LIMIT)?;
+        let anonymous_scopes = std::env::var("API_KEYS_ANONYMOUS_SCOPES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|scope| !scope.is_empty())
+            .map(|scope| match Scope::parse(scope) {
+                Some(Scope::PlaybackToken) | None => {
+                    anyhow::bail!("API_KEYS_ANONYMOUS_SCOPES: {} can't be anonymous", scope)
+                }
+                Some(scope) => Ok(scope),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let trust_forwarded_for = std::env::var("TRUST_X_FORWARDED_FOR")
+            .map(|value| value == "true")
+            .unwrap_or(false);
+
+        Ok(ApiKeys {
+            pool,
+            required,
+            default_rate_limit,
+            anonymous_scopes,
+            trust_forwarded_for,
+            keys: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn anonymous(&self) -> Access {
+        Access::anonymous(&self.anonymous_scopes)
+    }
+
+    /// Forgets the keys looked up, after a key is revoked.
+    pub fn invalidate(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+
+    async fn find(&self, key: &str) -> anyhow::Result<Option<db::ApiKeyRow>> {
+        let key_hash = hash(key);
+
+        if let Some((row, loaded)) = self.keys.lock().unwrap().get(&key_hash) {
+            if loaded.elapsed() < KEY_TTL {
+                return Ok(Some(row.clone()));
+            }
+        }
+
+        let row = db::get_api_key_by_hash(&self.pool, &key_hash).await?;
+        if let Some(row) = &row {
+            let now = Instant::now();
+            let mut keys = self.keys.lock().unwrap();
+            if keys.len() >= MAX_CACHED_KEYS {
+                keys.retain(|_, (_, loaded)| now.saturating_duration_since(*loaded) < KEY_TTL);
+            }
+            if keys.len() >= MAX_CACHED_KEYS {
+                let oldest = keys
+                    .iter()
+                    .min_by_key(|(_, (_, loaded))| *loaded)
+                    .map(|(key_hash, _)| key_hash.clone());
+                if let Some(oldest) = oldest {
+                    keys.remove(&oldest);
+                }
+            }
+            keys.insert(key_hash, (row.clone(), now));
+        }
+
+        Ok(row)
+    }
+
+    /// Counts an unknown key of `ip`. `Err` with the seconds to wait if it tried too many.
+    fn record_failure(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= MAX_FAILING_CLIENTS && !failures.contains_key(&ip) {
+            failures.retain(|_, bucket| !bucket.is_full(FAILED_LOOKUPS_PER_MINUTE, now));
+        }
+        failures
+            .entry(ip)
+            .or_insert_with(|| Bucket::new(FAILED_LOOKUPS_PER_MINUTE, now))
+            .take(FAILED_LOOKUPS_PER_MINUTE, now)
+            .map(|_| ())
+    }
+
+    /// The peer of the request, or the address the load balancer appended to
+    /// `X-Forwarded-For` with `TRUST_X_FORWARDED_FOR`.
+    fn client_ip<B>(&self, req: &Request<B>) -> IpAddr {
+        let forwarded_for = match self.trust_forwarded_for {
+            true => req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok()),
+            false => None,
+        };
+
+        // without the connection info (tests), every client is the same
+        forwarded_for
+            .or_else(|| {
+                req.extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|info| info.0.ip())
+            })
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    fn take(&self, key: &db::ApiKeyRow) -> Result<Quota, u64> {
+        let now = Instant::now();
+
+        self.buckets
+            .lock()
+            .unwrap()
+            .entry(key.id)
+            .or_insert_with(|| Bucket::new(key.rate_limit, now))
+            .take(key.rate_limit, now)
+    }
+}
+
+/// A new key, `bcp_` and 48 hex characters. Shown once, only its hash is saved.
+pub fn generate() -> anyhow::Result<String> {
+    let mut bytes = [0; 24];
+    openssl::rand::rand_bytes(&mut bytes)?;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    Ok(format!("{}{}", KEY_PREFIX, hex))
+}
+
+pub fn hash(key: &str) -> String {
+    openssl::sha::sha256(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Creates a key with `scopes`, returns it with the key itself.
+pub async fn create(
+    writer: &Writer,
+    name: String,
+    scopes: &[Scope],
+    rate_limit: u32,
+) -> anyhow::Result<(db::ApiKeyRow, String)> {
+    let key = generate()?;
+    let prefix = key[..KEY_PREFIX.len() + 8].to_string();
+    let key_hash = hash(&key);
+    let scopes = scopes
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let now = crate::unix_time_in_ms();
+
+    let row = db::ApiKeyRow {
+        id: 0,
+        name,
+        prefix,
+        scopes,
+        rate_limit,
+        created_at: now,
+        revoked_at: None,
+    };
+
+    let mut saved = row.clone();
+    saved.id = writer
+        .run(move |conn| {
+            Box::pin(async move {
+                db::save_api_key(
+                    conn,
+                    &row.name,
+                    &row.prefix,
+                    &key_hash,
+                    &row.scopes,
+                    row.rate_limit,
+                    row.created_at,
+                )
+                .await
+            })
+        })
+        .await?;
+
+    Ok((saved, key))
+}
+
+fn given_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+}
+
+fn quota_headers(headers: &mut HeaderMap, quota: &Quota) {
+    headers.insert("ratelimit-limit", HeaderValue::from(quota.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(quota.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(quota.reset));
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    let mut res = StatusCode::TOO_MANY_REQUESTS.into_response();
+    res.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    res
+}
+
+/// Middleware resolving the key of the request into an `Access`, `401` for an unknown (or
+/// missing, if required) key and `429` once its bucket is empty, or for the unknown keys of a
+/// client IP that tried too many. Needs the `Extension<Arc<ApiKeys>>` layered after it.
+pub async fn authenticate<B>(req: Request<B>, next: Next<B>) -> Response {
+    let mut res = resolve_key(req, next).await;
+
+    // what a response shows depends on the key, anonymous and refused ones included, so a
+    // shared cache doesn't serve them to another key
+    res.headers_mut().append(
+        header::VARY,
+        HeaderValue::from_static("X-API-Key, Authorization"),
+    );
+
+    res
+}
+
+async fn resolve_key<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let api_keys = match req.extensions().get::<Arc<ApiKeys>>() {
+        Some(api_keys) => api_keys.clone(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let key = match given_key(req.headers()) {
+        Some(key) => key.to_string(),
+        None if api_keys.required => return StatusCode::UNAUTHORIZED.into_response(),
+        None => {
+            let access = api_keys.anonymous();
+            req.extensions_mut().insert(access);
+            return next.run(req).await;
+        }
+    };
+
+    let key = match api_keys.find(&key).await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            return match api_keys.record_failure(api_keys.client_ip(&req)) {
+                Ok(()) => StatusCode::UNAUTHORIZED.into_response(),
+                Err(retry_after) => too_many_requests(retry_after),
+            };
+        }
+        Err(e) => {
+            log::error!(target: "api keys", "{}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let quota = match api_keys.take(&key) {
+        Ok(quota) => quota,
+        Err(retry_after) => {
+            let mut res = too_many_requests(retry_after);
+            quota_headers(
+                res.headers_mut(),
+                &Quota {
+                    limit: key.rate_limit,
+                    remaining: 0,
+                    reset: retry_after,
+                },
+            );
+            return res;
+        }
+    };
+
+    req.extensions_mut().insert(Access::key(&key));
+    let mut res = next.run(req).await;
+    quota_headers(res.headers_mut(), &quota);
+
+    res
+}
+
+#[test]
+fn token_bucket() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(2, start);
+
+    assert_eq!(
+        bucket.take(2, start),
+        Ok(Quota {
+            limit: 2,
+            remaining: 1,
+            reset: 30
+        })
+    );
+    assert_eq!(bucket.take(2, start).map(|q| q.remaining), Ok(0));
+    assert_eq!(bucket.take(2, start), Err(30));
+
+    // a token every 30s
+    assert_eq!(bucket.take(2, start + Duration::from_secs(29)), Err(1));
+    assert_eq!(
+        bucket
+            .take(2, start + Duration::from_secs(31))
+            .map(|q| q.remaining),
+        Ok(0)
+    );
+    assert!(bucket.take(2, start + Duration::from_secs(600)).is_ok());
+
+    // checking doesn't take
+    let mut bucket = Bucket::new(1, start);
+    assert_eq!(bucket.check(1, start), Ok(()));
+    assert_eq!(bucket.check(1, start), Ok(()));
+    assert!(bucket.is_full(1, start));
+    assert!(bucket.take(1, start).is_ok());
+    assert_eq!(bucket.check(1, start), Err(60));
+    assert!(!bucket.is_full(1, start + Duration::from_secs(59)));
+    assert!(bucket.is_full(1, start + Duration::from_secs(60)));
+}
+
+#[test]
+fn scopes() {
+    let key = |scopes: &str| db::ApiKeyRow {
+        id: 1,
+        name: "site".to_string(),
+        prefix: "bcp_00000000".to_string(),
+        scopes: scopes.to_string(),
+        rate_limit: 60,
+        created_at: 0,
+        revoked_at: None,
+    };
+
+    assert!(Access::key(&key("video_views")).can(Scope::VideoViews));
+    assert!(!Access::key(&key("")).can(Scope::VideoViews));
+    assert_eq!(
+        Access::key(&key("unknown")).require(Scope::VideoViews),
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert!(!Access::anonymous(&[]).can(Scope::VideoViews));
+    assert!(Access::anonymous(&[Scope::VideoViews]).can(Scope::VideoViews));
+
+    let generated = generate().unwrap();
+    assert_eq!(generated.len(), 52);
+    assert_eq!(hash(&generated).len(), 64);
+}
+
+#[tokio::test]
+async fn limit_only_the_unknown_keys() {
+    use axum::{extract::Extension, middleware::from_fn, routing::get, Router};
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    let key = generate().unwrap();
+    db::save_api_key(
+        &mut pool.acquire().await.unwrap(),
+        "site",
+        &key[..12],
+        &hash(&key),
+        "",
+        60,
+        0,
+    )
+    .await
+    .unwrap();
+
+    let api_keys = ApiKeys {
+        pool,
+        required: false,
+        default_rate_limit: DEFAULT_RATE_LIMIT,
+        anonymous_scopes: vec![],
+        trust_forwarded_for: false,
+        keys: Mutex::new(HashMap::new()),
+        buckets: Mutex::new(HashMap::new()),
+        failures: Mutex::new(HashMap::new()),
+    };
+    let app = Router::new()
+        .route("/videos", get(|| async { "videos" }))
+        .layer(from_fn(authenticate))
+        .layer(Extension(Arc::new(api_keys)));
+
+    let server = axum::Server::bind(&([127, 0, 0, 1], 0).into())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    let url = format!("http://{}/videos", server.local_addr());
+    tokio::spawn(server);
+
+    let client = reqwest::Client::new();
+    let get = |key: Option<&str>| {
+        let mut request = client.get(&url);
+        if let Some(key) = key {
+            request = request.header("x-api-key", key);
+        }
+        request.send()
+    };
+
+    for _ in 0..FAILED_LOOKUPS_PER_MINUTE {
+        let res = get(Some("bcp_unknown")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::VARY], "X-API-Key, Authorization");
+    }
+    let res = get(Some("bcp_unknown")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()[header::VARY], "X-API-Key, Authorization");
+
+    let res = get(Some(&key)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::VARY], "X-API-Key, Authorization");
+
+    let res = get(None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::VARY], "X-API-Key, Authorization");
+}
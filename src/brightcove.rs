@@ -1,9 +1,15 @@
+use futures::stream::Stream;
 use reqwest::header::ACCEPT;
 use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
+use std::future::Future;
 
 const VIDEOS_PER_PAGE: u32 = 25;
+const CMS_VIDEOS_PER_PAGE: u32 = 100;
+const PLAYLISTS_PER_PAGE: u32 = 100;
+const PLAYLIST_VIDEOS_LIMIT: u32 = 1000;
+pub const ANALYTICS_ITEMS_PER_PAGE: u32 = 1000;
 
 #[derive(Serialize, Deserialize)]
 pub struct AccessTokenResponse {
@@ -26,6 +32,59 @@ pub mod analytics {
         pub video: Option<String>,
         pub video_view: u32,
     }
+
+    /// Extra dimension the video views can be broken down by, paired with `video`.
+    #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Dimension {
+        Date,
+        DeviceType,
+        Country,
+    }
+
+    impl Dimension {
+        pub const ALL: [Dimension; 3] =
+            [Dimension::Date, Dimension::DeviceType, Dimension::Country];
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Dimension::Date => "date",
+                Dimension::DeviceType => "device_type",
+                Dimension::Country => "country",
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    pub struct BreakdownResponse {
+        pub item_count: u32,
+        pub items: Vec<BreakdownItem>,
+    }
+
+    /// A `video` + `<dimension>` row. `value` holds whichever of `date`, `device_type`
+    /// or `country` was requested.
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    pub struct BreakdownItem {
+        pub video: Option<String>,
+        #[serde(alias = "date", alias = "device_type", alias = "country")]
+        pub value: Option<String>,
+        pub video_view: u32,
+    }
+
+    /// Views per dimension value, summed over the selected videos. This is what the
+    /// proxy serves, it is not a BC api response.
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+    pub struct SummaryResponse {
+        pub dimension: Dimension,
+        pub item_count: u32,
+        pub items: Vec<SummaryItem>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::FromRow)]
+    pub struct SummaryItem {
+        pub value: String,
+        pub video_view: u32,
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -34,6 +93,93 @@ pub struct PlayerResponse {
     pub videos: Vec<Video>,
 }
 
+/// Playlist as listed by the CMS API, which unlike the Playback API can list them.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct CmsPlaylist {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// Video as listed by the CMS API. Unlike the Playback API it has every video of the account,
+/// whatever its state, schedule or geo restrictions.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct CmsVideo {
+    pub id: String,
+    /// `ACTIVE`, `INACTIVE`, `PENDING` or `DELETED`
+    pub state: String,
+    pub schedule: Option<CmsSchedule>,
+}
+
+/// Availability window of a video, ISO 8601 UTC dates.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct CmsSchedule {
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+}
+
+impl CmsVideo {
+    /// Whether the Playback API plays the video at `now` (unix time in seconds), geo
+    /// restrictions aside.
+    pub fn is_playable(&self, now: i64) -> bool {
+        if self.state != "ACTIVE" {
+            return false;
+        }
+
+        // `2022-03-20T12:30:00`, the dates compare as strings
+        let now = crate::site::rfc3339_from_unix(now);
+        let now = &now[..19];
+        let date = |date: &Option<String>| {
+            date.as_deref()
+                .filter(|d| d.len() >= 19)
+                .map(|d| d[..19].to_string())
+        };
+
+        match &self.schedule {
+            Some(schedule) => {
+                date(&schedule.starts_at).is_none_or(|starts_at| starts_at.as_str() <= now)
+                    && date(&schedule.ends_at).is_none_or(|ends_at| now < ends_at.as_str())
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CountResponse {
+    count: u32,
+}
+
+/// Playlist as returned by the Playback API. Only the ids of the videos are kept, in playlist
+/// order, the videos themselves are synced by `get_new_videos`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub videos: Vec<PlaylistVideo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PlaylistVideo {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PlaylistsResponse {
+    pub count: u32,
+    pub playlists: Vec<crate::db::PlaylistRow>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PlaylistResponse {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub count: u32,
+    pub videos: Vec<Video>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct Video {
     pub id: String,
@@ -41,6 +187,64 @@ pub struct Video {
     pub thumbnail: String,
     pub custom_fields: VideoCustomFields,
     pub video_views: Option<u32>,
+    pub poster: Option<String>,
+    /// in milliseconds
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    #[serde(default)]
+    pub text_tracks: Vec<TextTrack>,
+}
+
+/// A rendition of the video as returned by the Playback API.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Source {
+    pub src: Option<String>,
+    #[serde(rename = "type")]
+    pub mime_type: Option<String>,
+    pub container: Option<String>,
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub avg_bitrate: Option<u64>,
+    /// bytes, given for the mp4 renditions
+    pub size: Option<u64>,
+    /// DRM systems protecting the source, passed through as is
+    pub key_systems: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct TextTrack {
+    pub src: Option<String>,
+    pub srclang: Option<String>,
+    pub label: Option<String>,
+    pub kind: Option<String>,
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub default: bool,
+}
+
+/// Delivery format the `sources` can be filtered by.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    Hls,
+    Dash,
+    Mp4,
+}
+
+impl Delivery {
+    pub fn matches(&self, source: &Source) -> bool {
+        let mime_type = source.mime_type.as_deref().unwrap_or_default();
+
+        match self {
+            Delivery::Hls => {
+                mime_type == "application/x-mpegURL" || mime_type == "application/vnd.apple.mpegurl"
+            }
+            Delivery::Dash => mime_type == "application/dash+xml",
+            Delivery::Mp4 => mime_type == "video/mp4" || source.container.as_deref() == Some("MP4"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -58,23 +262,7 @@ pub struct VideoCustomFields {
 
 impl From<crate::db::VideoRow> for Video {
     fn from(video: crate::db::VideoRow) -> Self {
-        Video {
-            id: video.bc_video_id.clone(),
-            name: video.name.clone(),
-            thumbnail: video.thumbnail.clone(),
-            custom_fields: VideoCustomFields {
-                numero_corsa: video.numero_corsa.clone(),
-                data: video.data.clone(),
-                tipologia: video.tipologia.clone(),
-                cavalli: video.cavalli.clone(),
-                fantini: video.fantini.clone(),
-                primo: video.primo.clone(),
-                secondo: video.secondo.clone(),
-                terzo: video.terzo.clone(),
-                ippodromo: video.ippodromo.clone(),
-            },
-            video_views: Some(video.video_views),
-        }
+        Video::from(&video)
     }
 }
 
@@ -96,27 +284,43 @@ impl From<&crate::db::VideoRow> for Video {
                 ippodromo: video.ippodromo.clone(),
             },
             video_views: Some(video.video_views),
+            poster: video.poster.clone(),
+            duration: video.duration.map(|d| d as u64),
+            sources: video
+                .sources
+                .as_ref()
+                .and_then(|s| serde_json::from_str(s).ok())
+                .unwrap_or_default(),
+            text_tracks: video
+                .text_tracks
+                .as_ref()
+                .and_then(|t| serde_json::from_str(t).ok())
+                .unwrap_or_default(),
         }
     }
 }
 
-pub async fn get_access_token() -> String {
+pub async fn get_access_token() -> anyhow::Result<String> {
     let mut params = HashMap::new();
     params.insert("grant_type", "client_credentials");
 
     let client = reqwest::Client::new();
-    let res = client
+    let request = client
         .post("https://oauth.brightcove.com/v4/access_token")
         .basic_auth(dotenv!("CLIENT_ID"), Some(dotenv!("CLIENT_SECRET")))
         .form(&params)
-        .send()
-        .await
-        .expect("error requesting token")
-        .json::<AccessTokenResponse>()
-        .await
-        .expect("error deserializing response");
+        .send();
+    let res = crate::metrics::brightcove_call("oauth", request).await?;
+
+    let res: AccessTokenResponse = match res.status() {
+        StatusCode::OK => res.json().await?,
+        status_code => {
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
+        }
+    };
 
-    res.access_token
+    Ok(res.access_token)
 }
 
 pub(crate) async fn get_new_videos(
@@ -142,7 +346,17 @@ pub(crate) async fn get_new_videos(
     // videos. find a way to streamline it.
     while page <= max_pages && !stop_processing {
         log::debug!(target:"brightcove"," get_new_videos page {}/{}", page, max_pages);
-        let res = call_bc_player_url(page).await?;
+        let page_res = call_bc_player_url(page).await?;
+        // a video published or removed meanwhile shifts the pages, some would be seen twice
+        // or missed
+        if page_res.count != res.count {
+            anyhow::bail!(
+                "the videos changed while paging, {} then {}",
+                res.count,
+                page_res.count
+            );
+        }
+        let res = page_res;
 
         match &latest_bc_video_id {
             Some(latest_video_id) => {
@@ -195,6 +409,60 @@ pub(crate) async fn get_new_videos(
     }
 }
 
+/// The playable videos created between `from` and `to` (`YYYY-MM-DD`, both included), through
+/// the Playback API search. The policy key must be search enabled.
+pub(crate) async fn get_videos_created_between(
+    from: &str,
+    to: &str,
+) -> anyhow::Result<Vec<crate::db::VideoRow>> {
+    let url = format!(
+        "https://edge.api.brightcove.com/playback/v1/accounts/{}/videos",
+        dotenv!("ACCOUNT_ID"),
+    );
+    let accept_header = "application/json;pk=".to_string() + dotenv!("POLICY_KEY");
+    let q = format!("created_at:{}..{}", from, to);
+
+    let client = reqwest::Client::new();
+    let mut videos: Vec<crate::db::VideoRow> = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let (limit, page_offset) = (VIDEOS_PER_PAGE.to_string(), offset.to_string());
+        let request = client
+            .get(&url)
+            .query(&[
+                ("q", q.as_str()),
+                ("sort", "-created_at"),
+                ("limit", limit.as_str()),
+                ("offset", page_offset.as_str()),
+            ])
+            .header(ACCEPT, accept_header.as_str())
+            .send();
+        let res = crate::metrics::brightcove_call("playback", request)
+            .await?
+            .error_for_status()?
+            .json::<PlayerResponse>()
+            .await?;
+
+        log::debug!(
+            target: "brightcove",
+            "get_videos_created_between offset {}, {} videos of {}",
+            offset,
+            res.videos.len(),
+            res.count
+        );
+
+        videos.extend(res.videos.iter().map(|video| video.into()));
+        offset += VIDEOS_PER_PAGE;
+
+        if res.videos.is_empty() || offset >= res.count {
+            break;
+        }
+    }
+
+    Ok(videos)
+}
+
 pub async fn call_bc_player_url(page: u32) -> anyhow::Result<PlayerResponse> {
     let offset = VIDEOS_PER_PAGE * (page - 1);
     let url = format!(
@@ -207,19 +475,16 @@ pub async fn call_bc_player_url(page: u32) -> anyhow::Result<PlayerResponse> {
     let accept_header = "application/json;pk=".to_string() + dotenv!("POLICY_KEY");
 
     let client = reqwest::Client::new();
-    let res: PlayerResponse = client
-        .get(&url)
-        .header(ACCEPT, accept_header)
-        .send()
-        .await
-        .expect("error requesting ")
-        .json()
-        .await
-        .expect("error deserializing");
+    let request = client.get(&url).header(ACCEPT, accept_header).send();
+    let res = crate::metrics::brightcove_call("playback", request)
+        .await?
+        .error_for_status()?
+        .json::<PlayerResponse>()
+        .await?;
 
     log::debug!(
     target:"brightcove",
-            "call_bc_player_url page {}, offset {}, url {}, first: {}, last: {}",
+            "call_bc_player_url page {}, offset {}, url {}, first: {}, last: {:?}",
             page,
             offset,
             url,
@@ -227,48 +492,464 @@ pub async fn call_bc_player_url(page: u32) -> anyhow::Result<PlayerResponse> {
                 .iter()
                 .map(|v| v.id.clone() + ", ")
                 .collect::<String>(),
-            res.videos.last().unwrap().id,
+            res.videos.last().map(|v| &v.id),
         );
 
     Ok(res)
 }
 
-pub async fn get_all_video_views(token: &str) -> anyhow::Result<analytics::VideosResponse> {
+/// A single video from the Playback API, `None` if it isn't playable anymore (deleted,
+/// deactivated or out of its schedule).
+pub async fn get_video(video_id: &str) -> anyhow::Result<Option<Video>> {
+    let url = format!(
+        "https://edge.api.brightcove.com/playback/v1/accounts/{}/videos/{}",
+        dotenv!("ACCOUNT_ID"),
+        video_id
+    );
+
+    let accept_header = "application/json;pk=".to_string() + dotenv!("POLICY_KEY");
+
     let client = reqwest::Client::new();
+    let request = client.get(&url).header(ACCEPT, accept_header).send();
+    let res = crate::metrics::brightcove_call("playback", request).await?;
+
+    match res.status() {
+        StatusCode::OK => Ok(Some(res.json().await?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status_code => {
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
+        }
+    }
+}
 
+/// Every video of the account, from the CMS API. Fails if the number of videos changed
+/// while paging, as the pages would have shifted.
+pub async fn get_cms_videos(token: &str) -> anyhow::Result<Vec<CmsVideo>> {
+    let client = reqwest::Client::new();
+    let count = get_cms_video_count(&client, token).await?;
+
+    let mut videos: Vec<CmsVideo> = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        // oldest first, the videos created meanwhile go on the last page
+        let url = format!(
+            "https://cms.api.brightcove.com/v1/accounts/{}/videos?sort=created_at&limit={}&offset={}",
+            dotenv!("ACCOUNT_ID"),
+            CMS_VIDEOS_PER_PAGE,
+            offset
+        );
+
+        let request = client.get(&url).bearer_auth(token).send();
+        let res = crate::metrics::brightcove_call("cms", request).await?;
+
+        let page: Vec<CmsVideo> = match res.status() {
+            StatusCode::OK => res.json().await?,
+            status_code => {
+                let body = res.text().await?;
+                anyhow::bail!("Received response: {} {:#?}", status_code, body)
+            }
+        };
+
+        log::debug!(target:"brightcove","get_cms_videos offset {}, {} videos of {}", offset, page.len(), count);
+
+        let last_page = (page.len() as u32) < CMS_VIDEOS_PER_PAGE;
+        videos.extend(page);
+
+        if last_page {
+            break;
+        }
+        offset += CMS_VIDEOS_PER_PAGE;
+    }
+
+    let count_after = get_cms_video_count(&client, token).await?;
+    if count_after != count || videos.len() as u32 != count {
+        anyhow::bail!(
+            "the videos changed while paging, {} then {}, {} listed",
+            count,
+            count_after,
+            videos.len()
+        );
+    }
+
+    Ok(videos)
+}
+
+/// A single video from the CMS API, whatever its state, `None` if it's deleted.
+pub async fn get_cms_video(token: &str, video_id: &str) -> anyhow::Result<Option<CmsVideo>> {
     let url = format!(
-            "https://analytics.api.brightcove.com/v1/data?accounts={}&limit=all&dimensions=video&fields=video_view",
+        "https://cms.api.brightcove.com/v1/accounts/{}/videos/{}",
+        dotenv!("ACCOUNT_ID"),
+        video_id
+    );
+
+    let client = reqwest::Client::new();
+    let request = client.get(&url).bearer_auth(token).send();
+    let res = crate::metrics::brightcove_call("cms", request).await?;
+
+    match res.status() {
+        StatusCode::OK => Ok(Some(res.json().await?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status_code => {
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
+        }
+    }
+}
+
+async fn get_cms_video_count(client: &reqwest::Client, token: &str) -> anyhow::Result<u32> {
+    let url = format!(
+        "https://cms.api.brightcove.com/v1/accounts/{}/counts/videos",
+        dotenv!("ACCOUNT_ID"),
+    );
+
+    let request = client.get(&url).bearer_auth(token).send();
+    let res = crate::metrics::brightcove_call("cms", request).await?;
+
+    match res.status() {
+        StatusCode::OK => Ok(res.json::<CountResponse>().await?.count),
+        status_code => {
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
+        }
+    }
+}
+
+/// Every playlist of the account, from the CMS API.
+pub async fn get_playlists(token: &str) -> anyhow::Result<Vec<CmsPlaylist>> {
+    let client = reqwest::Client::new();
+    let mut playlists: Vec<CmsPlaylist> = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let url = format!(
+            "https://cms.api.brightcove.com/v1/accounts/{}/playlists?sort=name&limit={}&offset={}",
             dotenv!("ACCOUNT_ID"),
+            PLAYLISTS_PER_PAGE,
+            offset
         );
 
-    let video_views_res = client.get(url).bearer_auth(token).send().await?;
+        let request = client.get(&url).bearer_auth(token).send();
+        let res = crate::metrics::brightcove_call("cms", request).await?;
+
+        let page: Vec<CmsPlaylist> = match res.status() {
+            StatusCode::OK => res.json().await?,
+            status_code => {
+                let body = res.text().await?;
+                anyhow::bail!("Received response: {} {:#?}", status_code, body)
+            }
+        };
+
+        log::debug!(target:"brightcove","get_playlists offset {}, {} playlists", offset, page.len());
+
+        let last_page = (page.len() as u32) < PLAYLISTS_PER_PAGE;
+        playlists.extend(page);
+
+        if last_page {
+            break;
+        }
+        offset += PLAYLISTS_PER_PAGE;
+    }
+
+    Ok(playlists)
+}
+
+/// The playlist with its videos in order, from the Playback API.
+pub async fn get_playlist(playlist_id: &str) -> anyhow::Result<Playlist> {
+    let url = format!(
+        "https://edge.api.brightcove.com/playback/v1/accounts/{}/playlists/{}?limit={}",
+        dotenv!("ACCOUNT_ID"),
+        playlist_id,
+        PLAYLIST_VIDEOS_LIMIT
+    );
+
+    let accept_header = "application/json;pk=".to_string() + dotenv!("POLICY_KEY");
 
-    log::debug!(target:"brightcove","response: {:#?}", video_views_res);
-    match video_views_res.status() {
+    let client = reqwest::Client::new();
+    let request = client.get(&url).header(ACCEPT, accept_header).send();
+    let res = crate::metrics::brightcove_call("playback", request).await?;
+
+    match res.status() {
         StatusCode::OK => {
-            let body: analytics::VideosResponse = video_views_res.json().await?;
-            log::debug!(target:"brightcove","body: {:#?}", body);
-
-            // ignore video with NULL video id, fixes BC api
-            let valid_videos: Vec<analytics::Video> = body
-                .items
-                .into_iter()
-                .filter(|v| v.video.is_some())
-                .collect();
-
-            Ok(analytics::VideosResponse {
-                item_count: valid_videos.len() as u32,
-                items: valid_videos,
-            })
+            let playlist: Playlist = res.json().await?;
+            log::debug!(
+                target:"brightcove",
+                "get_playlist {} has {} videos",
+                playlist_id,
+                playlist.videos.len()
+            );
+            Ok(playlist)
+        }
+        status_code => {
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
         }
+    }
+}
+
+/// Page of the views of every video in the `from`..`to` window. `from` can be `alltime` or a
+/// unix time in milliseconds, like `to`.
+///
+/// `item_count` is the total number of rows Analytics has for the window, `analytics_pages`
+/// asks for every page. Rows are sorted by video so the pages don't overlap.
+pub async fn get_video_views_page(
+    token: &str,
+    from: &str,
+    to: &str,
+    offset: u32,
+) -> anyhow::Result<analytics::VideosResponse> {
+    let url = format!(
+            "https://analytics.api.brightcove.com/v1/data?accounts={}&limit={}&offset={}&dimensions=video&fields=video_view&sort=video&from={}&to={}",
+            dotenv!("ACCOUNT_ID"),
+            ANALYTICS_ITEMS_PER_PAGE,
+            offset,
+            from,
+            to,
+        );
+
+    let body: analytics::VideosResponse = call_bc_analytics_url(token, &url).await?;
+
+    log::debug!(
+        target:"brightcove",
+        "get_video_views_page offset {}, {} items of {}",
+        offset,
+        body.items.len(),
+        body.item_count
+    );
+
+    // ignore video with NULL video id, fixes BC api
+    Ok(analytics::VideosResponse {
+        item_count: body.item_count,
+        items: body
+            .items
+            .into_iter()
+            .filter(|v| v.video.is_some())
+            .collect(),
+    })
+}
+
+/// Live views for just `video_ids`, used when the db doesn't know them yet.
+pub async fn get_video_views(
+    token: &str,
+    video_ids: &[String],
+) -> anyhow::Result<analytics::VideosResponse> {
+    anyhow::ensure!(
+        video_ids
+            .iter()
+            .all(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit())),
+        "video ids have to be numeric"
+    );
+
+    let url = reqwest::Url::parse_with_params(
+        "https://analytics.api.brightcove.com/v1/data",
+        &[
+            ("accounts", dotenv!("ACCOUNT_ID")),
+            ("limit", "all"),
+            ("dimensions", "video"),
+            ("fields", "video,video_view"),
+            ("where", &format!("video=={}", video_ids.join(","))),
+        ],
+    )?;
+
+    let body: analytics::VideosResponse = call_bc_analytics_url(token, url.as_str()).await?;
+
+    let valid_videos: Vec<analytics::Video> = body
+        .items
+        .into_iter()
+        .filter(|v| v.video.is_some())
+        .collect();
+
+    Ok(analytics::VideosResponse {
+        item_count: valid_videos.len() as u32,
+        items: valid_videos,
+    })
+}
+
+/// Page of the `video` + `dimension` views, paged like `get_video_views_page`.
+pub async fn get_video_views_breakdown_page(
+    token: &str,
+    dimension: analytics::Dimension,
+    offset: u32,
+) -> anyhow::Result<analytics::BreakdownResponse> {
+    let url = format!(
+            "https://analytics.api.brightcove.com/v1/data?accounts={}&limit={}&offset={}&from=alltime&dimensions=video,{}&fields=video,{},video_view&sort=video,{}",
+            dotenv!("ACCOUNT_ID"),
+            ANALYTICS_ITEMS_PER_PAGE,
+            offset,
+            dimension.as_str(),
+            dimension.as_str(),
+            dimension.as_str(),
+        );
+
+    let body: analytics::BreakdownResponse = call_bc_analytics_url(token, &url).await?;
+
+    // same as get_video_views_page, BC returns rows with NULL video id
+    Ok(analytics::BreakdownResponse {
+        item_count: body.item_count,
+        items: body
+            .items
+            .into_iter()
+            .filter(|i| i.video.is_some() && i.value.is_some())
+            .collect(),
+    })
+}
+
+/// A page of an Analytics report.
+pub trait AnalyticsPage {
+    /// rows of the whole report
+    fn item_count(&self) -> u32;
+}
+
+impl AnalyticsPage for analytics::VideosResponse {
+    fn item_count(&self) -> u32 {
+        self.item_count
+    }
+}
+
+impl AnalyticsPage for analytics::BreakdownResponse {
+    fn item_count(&self) -> u32 {
+        self.item_count
+    }
+}
+
+/// Every page of an Analytics report, `fetch_page` gets the offset of each. Fails if the
+/// number of rows changes while paging, as the pages would have shifted.
+pub fn analytics_pages<P, F, Fut>(fetch_page: F) -> impl Stream<Item = anyhow::Result<P>>
+where
+    P: AnalyticsPage,
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = anyhow::Result<P>>,
+{
+    futures::stream::try_unfold(
+        (fetch_page, Some(0), None),
+        |(mut fetch_page, offset, item_count): (F, Option<u32>, Option<u32>)| async move {
+            let offset = match offset {
+                Some(offset) => offset,
+                None => return Ok(None),
+            };
+
+            let page = fetch_page(offset).await?;
+            if let Some(item_count) = item_count.filter(|&c| c != page.item_count()) {
+                anyhow::bail!(
+                    "the report changed while paging, {} rows then {}",
+                    item_count,
+                    page.item_count()
+                );
+            }
+
+            let next = offset + ANALYTICS_ITEMS_PER_PAGE;
+            let next = (next < page.item_count()).then_some(next);
+            let item_count = Some(page.item_count());
+
+            Ok(Some((page, (fetch_page, next, item_count))))
+        },
+    )
+}
+
+async fn call_bc_analytics_url<T: DeserializeOwned>(token: &str, url: &str) -> anyhow::Result<T> {
+    let client = reqwest::Client::new();
+
+    let request = client.get(url).bearer_auth(token).send();
+    let res = crate::metrics::brightcove_call("analytics", request).await?;
+
+    log::debug!(target:"brightcove","response: {:#?}", res);
+    match res.status() {
+        StatusCode::OK => Ok(res.json().await?),
 
         status_code => {
-            let body = &video_views_res.text().await.unwrap();
-            panic!("Received response: {} {:#?}", status_code, body);
+            let body = res.text().await?;
+            anyhow::bail!("Received response: {} {:#?}", status_code, body)
         }
     }
 }
 
+#[test]
+fn filter_sources_by_delivery() {
+    let src = r#"[
+        { "src": "https://.../master.m3u8", "type": "application/x-mpegURL" },
+        { "src": "https://.../manifest.mpd", "type": "application/dash+xml" },
+        { "src": "https://.../720.mp4", "container": "MP4", "height": 720, "width": 1280 },
+        { "src": "https://.../audio.m4a", "container": "M2TS" }
+    ]"#;
+
+    let sources: Vec<Source> = serde_json::from_str(src).unwrap();
+
+    let mp4: Vec<&Source> = sources
+        .iter()
+        .filter(|s| Delivery::Mp4.matches(s))
+        .collect();
+
+    assert_eq!(mp4.len(), 1);
+    assert_eq!(mp4[0].height, Some(720));
+    assert_eq!(
+        sources.iter().filter(|s| Delivery::Hls.matches(s)).count(),
+        1
+    );
+    assert_eq!(
+        sources.iter().filter(|s| Delivery::Dash.matches(s)).count(),
+        1
+    );
+}
+
+#[test]
+fn cms_video_playable() {
+    let video: CmsVideo = serde_json::from_str(
+        r#"{"id":"6301819598001","state":"ACTIVE","schedule":{"starts_at":"2022-03-20T10:00:00.000Z","ends_at":"2022-03-21T00:00:00.000Z"}}"#,
+    )
+    .unwrap();
+
+    // 2022-03-20T12:30:00Z
+    let now = 1_647_779_400;
+    assert!(video.is_playable(now));
+    assert!(!video.is_playable(now - 3 * 3600));
+    assert!(!video.is_playable(now + 86_400));
+    assert!(!CmsVideo {
+        state: "INACTIVE".to_string(),
+        ..video.clone()
+    }
+    .is_playable(now));
+    assert!(CmsVideo {
+        schedule: None,
+        ..video
+    }
+    .is_playable(0));
+}
+
+#[test]
+fn deserialize_analytics_breakdown() {
+    let src = r#" {
+	"item_count": 2,
+	"items": [{
+		"video": "v1_id",
+		"device_type": "mobile",
+		"video_view": 3
+	}, {
+		"video": "v1_id",
+		"device_type": "desktop",
+		"video_view": 1
+	}]
+} "#;
+
+    let item: analytics::BreakdownResponse = serde_json::from_str(src).unwrap();
+
+    assert_eq!(
+        item.items,
+        vec![
+            analytics::BreakdownItem {
+                video: Some("v1_id".to_string()),
+                value: Some("mobile".to_string()),
+                video_view: 3,
+            },
+            analytics::BreakdownItem {
+                video: Some("v1_id".to_string()),
+                value: Some("desktop".to_string()),
+                video_view: 1,
+            },
+        ]
+    );
+}
+
 #[test]
 fn deserialize_analytics_video() {
     let src = r#" {
@@ -311,3 +992,52 @@ fn deserialize_analytics_video() {
         }
     );
 }
+
+#[tokio::test]
+async fn page_analytics_reports() {
+    use futures::TryStreamExt;
+    use std::sync::Mutex;
+
+    let page = |item_count: u32, items: usize| analytics::VideosResponse {
+        item_count,
+        items: vec![
+            analytics::Video {
+                video: Some("6301819598001".to_string()),
+                video_view: 1,
+            };
+            items
+        ],
+    };
+
+    // 2500 rows, three pages
+    let offsets = Mutex::new(Vec::new());
+    let pages: Vec<_> = analytics_pages(|offset| {
+        offsets.lock().unwrap().push(offset);
+        let items = (2500 - offset).min(ANALYTICS_ITEMS_PER_PAGE) as usize;
+        async move { Ok(page(2500, items)) }
+    })
+    .try_collect()
+    .await
+    .unwrap();
+    assert_eq!(*offsets.lock().unwrap(), vec![0, 1000, 2000]);
+    assert_eq!(
+        pages.iter().map(|p| p.items.len()).collect::<Vec<_>>(),
+        vec![1000, 1000, 500]
+    );
+
+    // an empty report is a single page
+    let pages: Vec<_> = analytics_pages(|_| async { Ok(page(0, 0)) })
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(pages.len(), 1);
+
+    // rows added while paging
+    let result: anyhow::Result<Vec<_>> =
+        analytics_pages(
+            |offset| async move { Ok(page(if offset == 0 { 1500 } else { 1501 }, 1000)) },
+        )
+        .try_collect()
+        .await;
+    assert!(result.is_err());
+}
@@ -0,0 +1,405 @@
+//! In-process cache of the video reads, in front of `db::get_videos` and `db::get_video`.
+//!
+//! The sync tasks invalidate it as they write: new or deleted videos drop every listing,
+//! changed videos (views included) drop themselves and the listings showing them. Each
+//! invalidation also stamps the change, the `Last-Modified` of the responses, so a cached
+//! value comes with the stamp it was read at without asking the db.
+
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::brightcove::{PlayerResponse, Video};
+use crate::db::{self, VideoFilter};
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ListKey {
+    limit: u32,
+    offset: u32,
+    filter: VideoFilter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    List(ListKey),
+    Video(String),
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    List(Arc<PlayerResponse>),
+    Video(Arc<Video>),
+}
+
+impl Value {
+    /// Brightcove ids of the videos in the value, what invalidates it.
+    fn video_ids(&self) -> HashSet<String> {
+        match self {
+            Value::List(list) => list.videos.iter().map(|v| v.id.clone()).collect(),
+            Value::Video(video) => HashSet::from([video.id.clone()]),
+        }
+    }
+}
+
+struct Entry {
+    value: Value,
+    /// `changed_at` when the value was read
+    changed_at: Option<i64>,
+    video_ids: HashSet<String>,
+    /// approximate, the size of the value as json
+    bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    bytes: usize,
+    clock: u64,
+    /// bumped by every invalidation, a value read from the db before it may be stale
+    generation: u64,
+    /// unix time in milliseconds of the last change of the videos
+    changed_at: Option<i64>,
+}
+
+/// The state of the cache a value is read from the db at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Generation {
+    number: u64,
+    changed_at: Option<i64>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    fn remove_where(&mut self, remove: impl Fn(&Key, &Entry) -> bool) -> usize {
+        self.generation += 1;
+        self.changed_at = Some(crate::unix_time_in_ms());
+
+        let keys: Vec<Key> = self
+            .map
+            .iter()
+            .filter(|(key, entry)| remove(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &keys {
+            self.remove(key);
+        }
+
+        keys.len()
+    }
+
+    /// Drops the least recently used entry.
+    fn evict(&mut self) -> bool {
+        let key = match self.map.iter().min_by_key(|(_, entry)| entry.last_used) {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key);
+
+        true
+    }
+}
+
+/// Counters of the cache, served by `/admin/stats/cache`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+/// Bounded by `CACHE_MAX_ENTRIES` (1000 by default, 0 disables the cache) and
+/// `CACHE_MAX_BYTES` (64MB by default), the least recently used entries go first.
+pub struct VideoCache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl VideoCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        VideoCache {
+            entries: Mutex::new(Entries::default()),
+            max_entries,
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(VideoCache::new(
+            crate::env_var("CACHE_MAX_ENTRIES", DEFAULT_MAX_ENTRIES)?,
+            crate::env_var("CACHE_MAX_BYTES", DEFAULT_MAX_BYTES)?,
+        ))
+    }
+
+    /// Starts from the change stamp saved in the db, the invalidations move it from there.
+    pub async fn load_changed_at(&self, pool: &SqlitePool) -> anyhow::Result<()> {
+        let changed_at = db::get_videos_changed_at(pool).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.changed_at = entries.changed_at.max(changed_at);
+
+        Ok(())
+    }
+
+    /// Unix time in milliseconds of the last change of the videos, `None` before the first.
+    pub fn changed_at(&self) -> Option<i64> {
+        self.entries.lock().unwrap().changed_at
+    }
+
+    /// `db::get_videos`, cached, with the change stamp it was read at.
+    pub async fn get_videos(
+        &self,
+        pool: &SqlitePool,
+        limit: u32,
+        offset: u32,
+        filter: &VideoFilter,
+    ) -> anyhow::Result<(Arc<PlayerResponse>, Option<i64>)> {
+        let key = Key::List(ListKey {
+            limit,
+            offset,
+            filter: filter.clone(),
+        });
+
+        if let Some((Value::List(list), changed_at)) = self.get(&key) {
+            return Ok((list, changed_at));
+        }
+
+        let generation = self.generation();
+        let list = Arc::new(db::get_videos(pool, &limit, &offset, filter).await?);
+        self.insert(key, Value::List(list.clone()), generation);
+
+        Ok((list, generation.changed_at))
+    }
+
+    /// `db::get_video`, cached, with the change stamp it was read at. Missing videos aren't
+    /// cached, the sync may insert them anytime.
+    pub async fn get_video(
+        &self,
+        pool: &SqlitePool,
+        video_id: &str,
+    ) -> anyhow::Result<Option<(Arc<Video>, Option<i64>)>> {
+        let key = Key::Video(video_id.to_string());
+
+        if let Some((Value::Video(video), changed_at)) = self.get(&key) {
+            return Ok(Some((video, changed_at)));
+        }
+
+        let generation = self.generation();
+        let video = match db::get_video(pool, video_id).await? {
+            Some(video) => Arc::new(video),
+            None => return Ok(None),
+        };
+        self.insert(key, Value::Video(video.clone()), generation);
+
+        Ok(Some((video, generation.changed_at)))
+    }
+
+    /// New videos were inserted, or videos deleted: every listing may have changed.
+    pub fn invalidate_lists(&self) {
+        let removed = self
+            .entries
+            .lock()
+            .unwrap()
+            .remove_where(|key, _| matches!(key, Key::List(_)));
+
+        self.invalidations
+            .fetch_add(removed as u64, Ordering::Relaxed);
+    }
+
+    /// These videos changed: drops them and the listings showing them.
+    pub fn invalidate_videos<'a>(&self, video_ids: impl IntoIterator<Item = &'a str>) {
+        let video_ids: HashSet<&str> = video_ids.into_iter().collect();
+        if video_ids.is_empty() {
+            return;
+        }
+
+        let removed = self.entries.lock().unwrap().remove_where(|_, entry| {
+            entry
+                .video_ids
+                .iter()
+                .any(|id| video_ids.contains(id.as_str()))
+        });
+
+        self.invalidations
+            .fetch_add(removed as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+
+        CacheStats {
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn generation(&self) -> Generation {
+        let entries = self.entries.lock().unwrap();
+
+        Generation {
+            number: entries.generation,
+            changed_at: entries.changed_at,
+        }
+    }
+
+    /// The value and the change stamp it was read at.
+    fn get(&self, key: &Key) -> Option<(Value, Option<i64>)> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        match entries.map.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = clock;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((entry.value.clone(), entry.changed_at))
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `value` read from the db at `generation`, unless it has been invalidated since.
+    /// Such a value keeps the stamp it was read at: labelled older than it may be, a client
+    /// revalidating gets it again, never newer.
+    fn insert(&self, key: Key, value: Value, generation: Generation) {
+        let bytes = match &value {
+            Value::List(list) => serde_json::to_vec(list.as_ref()),
+            Value::Video(video) => serde_json::to_vec(video.as_ref()),
+        }
+        .map(|json| json.len())
+        .unwrap_or_default();
+
+        if self.max_entries == 0 || bytes > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation.number {
+            return;
+        }
+        entries.remove(&key);
+
+        while entries.map.len() >= self.max_entries || entries.bytes + bytes > self.max_bytes {
+            if !entries.evict() {
+                break;
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.bytes += bytes;
+        entries.map.insert(
+            key,
+            Entry {
+                video_ids: value.video_ids(),
+                value,
+                changed_at: generation.changed_at,
+                bytes,
+                last_used,
+            },
+        );
+    }
+}
+
+#[test]
+fn evict_and_invalidate() {
+    let video = |id: &str| -> Video {
+        serde_json::from_str(&format!(
+            r#"{{"id":"{}","name":"Corsa","thumbnail":"","video_views":1,"poster":null,"duration":null,
+            "custom_fields":{{"numero_corsa":"1","data":"2022/03/20","tipologia":null,"cavalli":null,"fantini":null,"primo":null,"secondo":null,"terzo":null,"ippodromo":"FIRENZE"}}}}"#,
+            id
+        ))
+        .unwrap()
+    };
+    let list_key = |offset: u32| {
+        Key::List(ListKey {
+            limit: 1,
+            offset,
+            filter: VideoFilter::default(),
+        })
+    };
+    let list = |id: &str| {
+        Value::List(Arc::new(PlayerResponse {
+            count: 2,
+            videos: vec![video(id)],
+        }))
+    };
+
+    let cache = VideoCache::new(2, DEFAULT_MAX_BYTES);
+    cache.insert(list_key(0), list("1"), cache.generation());
+    cache.insert(list_key(1), list("2"), cache.generation());
+    assert!(cache.get(&list_key(0)).is_some());
+
+    // the least recently used goes
+    cache.insert(
+        Key::Video("1".to_string()),
+        Value::Video(Arc::new(video("1"))),
+        cache.generation(),
+    );
+    assert!(cache.get(&list_key(1)).is_none());
+    assert_eq!(cache.stats().evictions, 1);
+
+    cache.invalidate_videos(["1"]);
+    assert_eq!(cache.stats().entries, 0);
+    assert_eq!(cache.stats().invalidations, 2);
+
+    cache.insert(list_key(0), list("1"), cache.generation());
+    cache.insert(
+        Key::Video("2".to_string()),
+        Value::Video(Arc::new(video("2"))),
+        cache.generation(),
+    );
+    cache.invalidate_lists();
+    assert!(cache.get(&Key::Video("2".to_string())).is_some());
+    assert!(cache.get(&list_key(0)).is_none());
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 2));
+
+    // read before an invalidation
+    let generation = cache.generation();
+    cache.invalidate_videos(["2"]);
+    cache.insert(list_key(1), list("2"), generation);
+    assert!(cache.get(&list_key(1)).is_none());
+
+    // cached with the stamp of the last invalidation before the read
+    let changed_at = cache.changed_at();
+    assert!(changed_at.is_some());
+    cache.insert(list_key(1), list("2"), cache.generation());
+    assert_eq!(cache.get(&list_key(1)).map(|(_, at)| at), Some(changed_at));
+
+    let tiny = VideoCache::new(10, 10);
+    tiny.insert(list_key(0), list("1"), tiny.generation());
+    assert_eq!(tiny.stats().entries, 0);
+}
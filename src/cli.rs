@@ -0,0 +1,238 @@
+//! Subcommands of the binary. Without one it serves, as before the subcommands.
+
+use std::net::SocketAddr;
+
+use crate::api_keys::Scope;
+
+pub const USAGE: &str = "\
+usage: brightcove-rs-proxy [command]
+
+commands:
+  serve [--bind ADDR] [--no-sync]   serve the API (the default), --no-sync starts the syncs
+                                    paused, see /admin
+  sync videos|views [--once]        run the video or views sync on its interval, or once
+  migrate                           run the db migrations
+  backfill --from DATE --to DATE    pull again the videos created in the range, YYYY-MM-DD
+  token                             print a new Brightcove OAuth token
+  api-keys list                     list the API keys
+  api-keys create --name NAME [--scope SCOPE]... [--rate-limit N]
+                                    create an API key, printed once
+  api-keys revoke ID                revoke an API key
+";
+
+const DEFAULT_BIND: &str = "0.0.0.0:4000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncTarget {
+    Videos,
+    Views,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ApiKeysCommand {
+    List,
+    Create {
+        name: String,
+        scopes: Vec<Scope>,
+        rate_limit: Option<u32>,
+    },
+    Revoke(i64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve { bind: SocketAddr, sync: bool },
+    Sync { target: SyncTarget, once: bool },
+    Migrate,
+    Backfill { from: String, to: String },
+    Token,
+    ApiKeys(ApiKeysCommand),
+    Help,
+}
+
+/// Parses the arguments, without the program name.
+pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Command> {
+    let mut args = args.into_iter();
+
+    let command = match args.next().as_deref() {
+        None | Some("serve") => {
+            let mut bind = DEFAULT_BIND.parse()?;
+            let mut sync = true;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--bind" => {
+                        bind = value(&mut args, "--bind")?
+                            .parse()
+                            .map_err(|e| anyhow::anyhow!("invalid --bind: {}", e))?
+                    }
+                    "--no-sync" => sync = false,
+                    _ => return unexpected(&arg),
+                }
+            }
+            Command::Serve { bind, sync }
+        }
+        Some("sync") => {
+            let target = match args.next().as_deref() {
+                Some("videos") => SyncTarget::Videos,
+                Some("views") => SyncTarget::Views,
+                _ => anyhow::bail!("sync needs videos or views"),
+            };
+            let mut once = false;
+            for arg in args.by_ref() {
+                match arg.as_str() {
+                    "--once" => once = true,
+                    _ => return unexpected(&arg),
+                }
+            }
+            Command::Sync { target, once }
+        }
+        Some("migrate") => Command::Migrate,
+        Some("backfill") => {
+            let (mut from, mut to) = (None, None);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--from" => from = Some(date(value(&mut args, "--from")?)?),
+                    "--to" => to = Some(date(value(&mut args, "--to")?)?),
+                    _ => return unexpected(&arg),
+                }
+            }
+            match (from, to) {
+                (Some(from), Some(to)) if from <= to => Command::Backfill { from, to },
+                (Some(_), Some(_)) => anyhow::bail!("--from is after --to"),
+                _ => anyhow::bail!("backfill needs --from and --to"),
+            }
+        }
+        Some("token") => Command::Token,
+        Some("api-keys") => Command::ApiKeys(api_keys(&mut args)?),
+        Some("help" | "--help" | "-h") => Command::Help,
+        Some(command) => anyhow::bail!("unknown command {}", command),
+    };
+
+    if let Some(arg) = args.next() {
+        return unexpected(&arg);
+    }
+
+    Ok(command)
+}
+
+fn api_keys(args: &mut impl Iterator<Item = String>) -> anyhow::Result<ApiKeysCommand> {
+    match args.next().as_deref() {
+        Some("list") => Ok(ApiKeysCommand::List),
+        Some("create") => {
+            let (mut name, mut scopes, mut rate_limit) = (None, Vec::new(), None);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--name" => name = Some(value(args, "--name")?),
+                    "--scope" => {
+                        let scope = value(args, "--scope")?;
+                        scopes.push(
+                            Scope::parse(&scope)
+                                .ok_or_else(|| anyhow::anyhow!("unknown scope {}", scope))?,
+                        );
+                    }
+                    "--rate-limit" => {
+                        rate_limit = Some(
+                            value(args, "--rate-limit")?
+                                .parse()
+                                .map_err(|e| anyhow::anyhow!("invalid --rate-limit: {}", e))?,
+                        )
+                    }
+                    _ => return unexpected(&arg),
+                }
+            }
+            Ok(ApiKeysCommand::Create {
+                name: name.ok_or_else(|| anyhow::anyhow!("api-keys create needs --name"))?,
+                scopes,
+                rate_limit,
+            })
+        }
+        Some("revoke") => {
+            let id = value(args, "api-keys revoke")?;
+            id.parse()
+                .map(ApiKeysCommand::Revoke)
+                .map_err(|e| anyhow::anyhow!("invalid id {}: {}", id, e))
+        }
+        _ => anyhow::bail!("api-keys needs list, create or revoke"),
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> anyhow::Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow::anyhow!("{} needs a value", name))
+}
+
+fn unexpected<T>(arg: &str) -> anyhow::Result<T> {
+    anyhow::bail!("unexpected argument {}", arg)
+}
+
+/// `YYYY-MM-DD`, as the Playback API search takes it.
+fn date(date: String) -> anyhow::Result<String> {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let parts: Vec<&str> = date.split('-').collect();
+
+    match parts.as_slice() {
+        [y, m, d]
+            if y.len() == 4 && m.len() == 2 && d.len() == 2 && parts.iter().all(|p| digits(p)) =>
+        {
+            Ok(date)
+        }
+        _ => anyhow::bail!("invalid date {}, expected YYYY-MM-DD", date),
+    }
+}
+
+#[test]
+fn parse_commands() {
+    let parse = |args: &[&str]| parse(args.iter().map(|a| a.to_string()));
+
+    assert_eq!(
+        parse(&[]).unwrap(),
+        Command::Serve {
+            bind: DEFAULT_BIND.parse().unwrap(),
+            sync: true
+        }
+    );
+    assert_eq!(
+        parse(&["serve", "--bind", "127.0.0.1:8080", "--no-sync"]).unwrap(),
+        Command::Serve {
+            bind: "127.0.0.1:8080".parse().unwrap(),
+            sync: false
+        }
+    );
+    assert_eq!(
+        parse(&["sync", "views", "--once"]).unwrap(),
+        Command::Sync {
+            target: SyncTarget::Views,
+            once: true
+        }
+    );
+    assert_eq!(
+        parse(&["backfill", "--from", "2022-03-01", "--to", "2022-03-31"]).unwrap(),
+        Command::Backfill {
+            from: "2022-03-01".to_string(),
+            to: "2022-03-31".to_string()
+        }
+    );
+    assert_eq!(
+        parse(&[
+            "api-keys",
+            "create",
+            "--name",
+            "site",
+            "--scope",
+            "video_views"
+        ])
+        .unwrap(),
+        Command::ApiKeys(ApiKeysCommand::Create {
+            name: "site".to_string(),
+            scopes: vec![Scope::VideoViews],
+            rate_limit: None
+        })
+    );
+
+    assert!(parse(&["sync"]).is_err());
+    assert!(parse(&["sync", "videos", "--twice"]).is_err());
+    assert!(parse(&["backfill", "--from", "2022-3-1", "--to", "2022-03-31"]).is_err());
+    assert!(parse(&["backfill", "--from", "2022-04-01", "--to", "2022-03-31"]).is_err());
+    assert!(parse(&["serve", "--bind"]).is_err());
+    assert!(parse(&["token", "now"]).is_err());
+}
@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use sqlx::Connection;
+
+use crate::brightcove::analytics::{BreakdownItem, Dimension, SummaryItem, SummaryResponse};
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::FromRow)]
 pub struct VideoRow {
@@ -15,8 +18,35 @@ pub struct VideoRow {
     pub ippodromo: String,
     pub video_views: u32,
     pub bc_video_id: String,
+    pub poster: Option<String>,
+    pub duration: Option<i64>,
+    /// `brightcove::Source` list, as json
+    pub sources: Option<String>,
+    /// `brightcove::TextTrack` list, as json
+    pub text_tracks: Option<String>,
 }
 
+/// Columns `VideoRow` is read from.
+pub(crate) const VIDEO_COLUMNS: &str = r#"
+                name,
+                thumbnail,
+                numero_corsa,
+                data,
+                tipologia,
+                cavalli,
+                fantini,
+                primo,
+                secondo,
+                terzo,
+                ippodromo,
+                video_views,
+                bc_video_id,
+                poster,
+                duration,
+                sources,
+                text_tracks
+"#;
+
 impl From<&crate::brightcove::Video> for VideoRow {
     fn from(video: &crate::brightcove::Video) -> Self {
         VideoRow {
@@ -33,30 +63,134 @@ impl From<&crate::brightcove::Video> for VideoRow {
             terzo: video.custom_fields.terzo.clone(),
             ippodromo: video.custom_fields.ippodromo.clone(),
             video_views: 0,
+            poster: video.poster.clone(),
+            duration: video.duration.map(|d| d as i64),
+            sources: serde_json::to_string(&video.sources).ok(),
+            text_tracks: serde_json::to_string(&video.text_tracks).ok(),
         }
     }
 }
 
-pub async fn update_video_views(
-    conn: &mut sqlx::pool::PoolConnection<sqlx::Sqlite>,
-    bc_video_id: &str,
-    views: &u32,
-) -> anyhow::Result<bool> {
-    let query_str = format!(
-        "UPDATE videos SET video_views = {} WHERE bc_video_id = {}",
-        views, bc_video_id
-    );
+/// How a batch of Analytics views is applied to `videos.video_views`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewsSync {
+    /// views since `alltime`, they replace the stored totals
+    Full,
+    /// views since the last sync, they are added to the stored totals
+    Delta,
+}
 
-    let query = sqlx::query(&query_str);
+/// Applies a page of Analytics views in one transaction.
+pub(crate) async fn save_video_views(
+    conn: &mut sqlx::SqliteConnection,
+    items: &[crate::brightcove::analytics::Video],
+    sync: ViewsSync,
+) -> anyhow::Result<u64> {
+    let mut tx = conn.begin().await?;
+    let mut rows_affected = 0;
 
-    let rows_affected = query.execute(conn).await?.rows_affected();
+    let query_str = match sync {
+        ViewsSync::Full => "UPDATE videos SET video_views = ? WHERE bc_video_id = ?",
+        ViewsSync::Delta => "UPDATE videos SET video_views = video_views + ? WHERE bc_video_id = ?",
+    };
 
-    Ok(rows_affected > 0)
+    for item in items {
+        if let Some(bc_video_id) = &item.video {
+            rows_affected += sqlx::query(query_str)
+                .bind(item.video_view)
+                .bind(bc_video_id)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+        }
+    }
+
+    tx.commit().await?;
+
+    if rows_affected > 0 {
+        set_videos_changed(conn).await?;
+    }
+
+    Ok(rows_affected)
+}
+
+/// Records a completed views sync, the next delta starts from `synced_at`.
+pub(crate) async fn set_views_synced_at(
+    conn: &mut sqlx::SqliteConnection,
+    sync: ViewsSync,
+    synced_at: i64,
+) -> anyhow::Result<()> {
+    set_synced_at(&mut *conn, "views", synced_at).await?;
+    if sync == ViewsSync::Full {
+        set_synced_at(&mut *conn, "views_full", synced_at).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn get_synced_at(
+    conn: &mut sqlx::SqliteConnection,
+    name: &str,
+) -> anyhow::Result<Option<i64>> {
+    let row: Option<(i64,)> = sqlx::query_as("SELECT synced_at FROM sync_state WHERE name = ?")
+        .bind(name)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(row.map(|r| r.0))
+}
+
+pub(crate) async fn clear_synced_at(
+    conn: &mut sqlx::SqliteConnection,
+    name: &str,
+) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM sync_state WHERE name = ?")
+        .bind(name)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn set_synced_at(
+    conn: &mut sqlx::SqliteConnection,
+    name: &str,
+    synced_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO sync_state (name, synced_at)
+        VALUES (?, ?)
+        ON CONFLICT (name) DO UPDATE SET synced_at = excluded.synced_at
+        "#,
+    )
+    .bind(name)
+    .bind(synced_at)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// `sync_state` entry of the last time any video row changed: inserted, updated, deleted or
+/// with new views. It's the `Last-Modified` of what's served from the videos.
+pub(crate) const VIDEOS_CHANGED: &str = "videos_changed";
+
+pub(crate) async fn set_videos_changed(conn: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
+    set_synced_at(conn, VIDEOS_CHANGED, crate::unix_time_in_ms()).await
+}
+
+pub(crate) async fn get_videos_changed_at(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> anyhow::Result<Option<i64>> {
+    let mut conn = pool.acquire().await?;
+
+    get_synced_at(&mut conn, VIDEOS_CHANGED).await
 }
 
 pub async fn get_latest_bc_video_id(
     //pool: &sqlx::Pool<sqlx::Sqlite>,
-    conn: &mut sqlx::pool::PoolConnection<sqlx::Sqlite>,
+    conn: &mut sqlx::SqliteConnection,
 ) -> anyhow::Result<Option<String>> {
     let row: (String,) = sqlx::query_as(
         r#"
@@ -74,10 +208,13 @@ pub async fn get_latest_bc_video_id(
     Ok(Some(row.0))
 }
 
+/// Inserts the videos with their races in one transaction, none is saved if one fails.
 pub(crate) async fn save_videos(
-    conn: &mut sqlx::pool::PoolConnection<sqlx::Sqlite>,
+    conn: &mut sqlx::SqliteConnection,
     new_videos: &Vec<VideoRow>,
 ) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+
     for video in new_videos {
         let id = sqlx::query(
             r#"
@@ -94,7 +231,11 @@ pub(crate) async fn save_videos(
             terzo,
             ippodromo,
             video_views,
-            bc_video_id
+            bc_video_id,
+            poster,
+            duration,
+            sources,
+            text_tracks
  )
         VALUES (
             ?,
@@ -109,6 +250,10 @@ pub(crate) async fn save_videos(
             ?,
             ?,
             ?,
+            ?,
+            ?,
+            ?,
+            ?,
             ?
   )
         "#,
@@ -124,115 +269,1328 @@ pub(crate) async fn save_videos(
         .bind(&video.secondo)
         .bind(&video.terzo)
         .bind(&video.ippodromo)
-        .bind(&video.video_views)
+        .bind(video.video_views)
         .bind(&video.bc_video_id)
-        .execute(&mut *conn)
+        .bind(&video.poster)
+        .bind(video.duration)
+        .bind(&video.sources)
+        .bind(&video.text_tracks)
+        .execute(&mut *tx)
         .await?
         .last_insert_rowid();
 
         log::debug!(target:"db", "saved video: {} - {}", id, &video.bc_video_id);
+
+        let race_id = save_race(&mut tx, video).await?;
+        sqlx::query("UPDATE videos SET race_id = ? WHERE id = ?")
+            .bind(race_id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    if !new_videos.is_empty() {
+        set_videos_changed(&mut tx).await?;
     }
 
+    tx.commit().await?;
+
     Ok(())
 }
 
-pub(crate) async fn get_videos(
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-    limit: &u32,
-    offset: &u32,
-) -> crate::brightcove::PlayerResponse {
-    let mut conn = pool.acquire().await.unwrap();
+/// Updates an existing video with the fields from Brightcove, views are kept, in one
+/// transaction with its race. A video that was unavailable is available again. Returns false
+/// if the video isn't in the db.
+pub(crate) async fn update_video(
+    conn: &mut sqlx::SqliteConnection,
+    video: &VideoRow,
+) -> anyhow::Result<bool> {
+    let mut tx = conn.begin().await?;
 
-    let (count,): (u32,) = sqlx::query_as("SELECT COUNT(*) FROM videos")
-        .fetch_one(&mut conn)
-        .await
-        .unwrap();
+    let rows_affected = sqlx::query(
+        r#"
+        UPDATE videos SET
+            name = ?,
+            thumbnail = ?,
+            numero_corsa = ?,
+            data = ?,
+            tipologia = ?,
+            cavalli = ?,
+            fantini = ?,
+            primo = ?,
+            secondo = ?,
+            terzo = ?,
+            ippodromo = ?,
+            poster = ?,
+            duration = ?,
+            sources = ?,
+            text_tracks = ?,
+            unavailable_at = NULL
+        WHERE bc_video_id = ?
+        "#,
+    )
+    .bind(&video.name)
+    .bind(&video.thumbnail)
+    .bind(&video.numero_corsa)
+    .bind(&video.data)
+    .bind(&video.tipologia)
+    .bind(&video.cavalli)
+    .bind(&video.fantini)
+    .bind(&video.primo)
+    .bind(&video.secondo)
+    .bind(&video.terzo)
+    .bind(&video.ippodromo)
+    .bind(&video.poster)
+    .bind(video.duration)
+    .bind(&video.sources)
+    .bind(&video.text_tracks)
+    .bind(&video.bc_video_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    // the race fields may have been fixed, or the video moved to another race
+    let (old_race_id,): (Option<i64>,) =
+        sqlx::query_as("SELECT race_id FROM videos WHERE bc_video_id = ?")
+            .bind(&video.bc_video_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    let race_id = save_race(&mut tx, video).await?;
+    sqlx::query("UPDATE videos SET race_id = ? WHERE bc_video_id = ?")
+        .bind(race_id)
+        .bind(&video.bc_video_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // the race it left may have no video anymore
+    if let Some(old_race_id) = old_race_id.filter(|id| *id != race_id) {
+        sqlx::query(
+            "DELETE FROM races WHERE id = ? AND NOT EXISTS (SELECT 1 FROM videos WHERE race_id = ?)",
+        )
+        .bind(old_race_id)
+        .bind(old_race_id)
+        .execute(&mut *tx)
+        .await?;
+    }
 
-    let video_rows: Vec<VideoRow> = sqlx::query_as(
+    set_videos_changed(&mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Hides a video Brightcove doesn't play anymore, its row stays for when it comes back.
+/// Returns false if the video isn't in the db or was already unavailable.
+pub(crate) async fn set_video_unavailable(
+    conn: &mut sqlx::SqliteConnection,
+    bc_video_id: &str,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        "UPDATE videos SET unavailable_at = ? WHERE bc_video_id = ? AND unavailable_at IS NULL",
+    )
+    .bind(now)
+    .bind(bc_video_id)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    if rows_affected > 0 {
+        set_videos_changed(conn).await?;
+    }
+
+    Ok(rows_affected > 0)
+}
+
+/// Race the video belongs to, keyed by `data`, `ippodromo` and `numero_corsa` as
+/// `racedays::race_key`. The race fields of a later video of the same race win, unless they
+/// are empty.
+async fn save_race(conn: &mut sqlx::SqliteConnection, video: &VideoRow) -> anyhow::Result<i64> {
+    let numero_corsa = crate::racedays::race_key(&video.numero_corsa);
+
+    sqlx::query(
+        r#"
+        INSERT INTO races (data, ippodromo, numero_corsa, tipologia, cavalli, fantini, primo, secondo, terzo)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (data, ippodromo, numero_corsa) DO UPDATE SET
+            tipologia = COALESCE(excluded.tipologia, races.tipologia),
+            cavalli = COALESCE(excluded.cavalli, races.cavalli),
+            fantini = COALESCE(excluded.fantini, races.fantini),
+            primo = COALESCE(excluded.primo, races.primo),
+            secondo = COALESCE(excluded.secondo, races.secondo),
+            terzo = COALESCE(excluded.terzo, races.terzo)
+        "#,
+    )
+    .bind(&video.data)
+    .bind(&video.ippodromo)
+    .bind(&numero_corsa)
+    .bind(&video.tipologia)
+    .bind(&video.cavalli)
+    .bind(&video.fantini)
+    .bind(&video.primo)
+    .bind(&video.secondo)
+    .bind(&video.terzo)
+    .execute(&mut *conn)
+    .await?;
+
+    let (race_id,): (i64,) = sqlx::query_as(
+        "SELECT id FROM races WHERE data = ? AND ippodromo = ? AND numero_corsa = ?",
+    )
+    .bind(&video.data)
+    .bind(&video.ippodromo)
+    .bind(&numero_corsa)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(race_id)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::FromRow)]
+pub struct RaceRow {
+    #[serde(skip)]
+    pub id: i64,
+    pub data: String,
+    pub ippodromo: String,
+    pub numero_corsa: String,
+    pub tipologia: Option<String>,
+    pub cavalli: Option<String>,
+    pub fantini: Option<String>,
+    pub primo: Option<String>,
+    pub secondo: Option<String>,
+    pub terzo: Option<String>,
+}
+
+/// The race of the `data` day at `ippodromo` (any case) numbered `numero_corsa`, where `"2"`
+/// and `"02"` are the same race, with its videos.
+pub(crate) async fn get_race(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    data: &str,
+    ippodromo: &str,
+    numero_corsa: &str,
+) -> anyhow::Result<Option<crate::racedays::RaceResponse>> {
+    let mut conn = pool.acquire().await?;
+
+    let race: Option<RaceRow> = sqlx::query_as(
         r#"
             select
-                name,
-                thumbnail,
-                numero_corsa,
+                id,
                 data,
+                ippodromo,
+                numero_corsa,
                 tipologia,
                 cavalli,
                 fantini,
                 primo,
                 secondo,
-                terzo,
-                ippodromo,
-                video_views,
-                bc_video_id
+                terzo
+            from races
+            where data = ? AND ippodromo = ? COLLATE NOCASE AND numero_corsa = ?
+            ORDER BY id
+        "#,
+    )
+    .bind(data)
+    .bind(ippodromo)
+    .bind(crate::racedays::race_key(numero_corsa))
+    .fetch_optional(&mut conn)
+    .await?;
+
+    let race = match race {
+        Some(race) => race,
+        None => return Ok(None),
+    };
+
+    let video_rows: Vec<VideoRow> = sqlx::query_as(&format!(
+        r#"
+            select {}
             from videos
+            where race_id = ? AND unavailable_at IS NULL
+            ORDER BY bc_video_id
+        "#,
+        VIDEO_COLUMNS
+    ))
+    .bind(race.id)
+    .fetch_all(&mut conn)
+    .await?;
+
+    // every video of the race is unavailable
+    if video_rows.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(crate::racedays::RaceResponse {
+        race,
+        videos: video_rows.iter().map(|v| v.into()).collect(),
+    }))
+}
+
+/// Filters shared by the video listing and the video stream. `date` is `YYYY-MM-DD`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct VideoFilter {
+    pub ippodromo: Option<String>,
+    pub tipologia: Option<String>,
+    pub date: Option<String>,
+}
+
+impl VideoFilter {
+    /// `false` if `date` isn't a `YYYY-MM-DD` date, the handlers answer `400` then.
+    pub fn is_valid(&self) -> bool {
+        match &self.date {
+            Some(date) => crate::racedays::to_data(date).is_some(),
+            None => true,
+        }
+    }
+
+    /// `AND ...` conditions to append to a `where`, with the values to bind in order.
+    fn sql(&self) -> (String, Vec<String>) {
+        let mut sql = String::new();
+        let mut values = vec![];
+
+        if let Some(ippodromo) = &self.ippodromo {
+            sql.push_str(" AND ippodromo = ? COLLATE NOCASE");
+            values.push(ippodromo.clone());
+        }
+        if let Some(tipologia) = &self.tipologia {
+            sql.push_str(" AND tipologia = ? COLLATE NOCASE");
+            values.push(tipologia.clone());
+        }
+        if let Some(date) = &self.date {
+            sql.push_str(" AND data = ?");
+            // an invalid date, refused by `is_valid`, matches nothing
+            values.push(crate::racedays::to_data(date).unwrap_or_default());
+        }
+
+        (sql, values)
+    }
+}
+
+pub(crate) async fn get_videos(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    limit: &u32,
+    offset: &u32,
+    filter: &VideoFilter,
+) -> anyhow::Result<crate::brightcove::PlayerResponse> {
+    let mut conn = pool.acquire().await?;
+
+    let (filter_sql, filter_values) = filter.sql();
+
+    let count_sql = format!(
+        "SELECT COUNT(*) FROM videos WHERE unavailable_at IS NULL {}",
+        filter_sql
+    );
+    let mut count_query = sqlx::query_as::<_, (u32,)>(&count_sql);
+    for value in &filter_values {
+        count_query = count_query.bind(value);
+    }
+    let (count,) = count_query.fetch_one(&mut conn).await?;
+
+    let videos_sql = format!(
+        r#"
+            select {}
+            from videos
+            where unavailable_at IS NULL {}
             ORDER BY data DESC LIMIT ? OFFSET ?
         "#,
-    )
+        VIDEO_COLUMNS, filter_sql
+    );
+    let mut videos_query = sqlx::query_as::<_, VideoRow>(&videos_sql);
+    for value in &filter_values {
+        videos_query = videos_query.bind(value);
+    }
+    let video_rows: Vec<VideoRow> = videos_query
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&mut conn)
+        .await?;
+
+    let videos: Vec<crate::brightcove::Video> = video_rows.iter().map(|v| v.into()).collect();
+
+    Ok(crate::brightcove::PlayerResponse { count, videos })
+}
+
+/// A video with its row id, which orders the videos by insertion.
+pub struct VideoWithId {
+    pub id: i64,
+    pub video: crate::brightcove::Video,
+}
+
+impl<'r> sqlx::FromRow<'r, sqlx::sqlite::SqliteRow> for VideoWithId {
+    fn from_row(row: &'r sqlx::sqlite::SqliteRow) -> Result<Self, sqlx::Error> {
+        use sqlx::Row;
+
+        Ok(VideoWithId {
+            id: row.try_get("id")?,
+            video: VideoRow::from_row(row)?.into(),
+        })
+    }
+}
+
+/// Videos inserted after the `after_id` row matching `filter`, in insertion order.
+pub(crate) async fn get_videos_after(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    after_id: i64,
+    filter: &VideoFilter,
+    limit: u32,
+) -> anyhow::Result<Vec<VideoWithId>> {
+    let mut conn = pool.acquire().await?;
+
+    let (filter_sql, filter_values) = filter.sql();
+
+    let query_str = format!(
+        r#"
+            select id, {}
+            from videos
+            where id > ? AND unavailable_at IS NULL {}
+            ORDER BY id LIMIT ?
+        "#,
+        VIDEO_COLUMNS, filter_sql
+    );
+
+    let mut query = sqlx::query_as::<_, VideoWithId>(&query_str).bind(after_id);
+    for value in &filter_values {
+        query = query.bind(value);
+    }
+
+    Ok(query.bind(limit).fetch_all(&mut conn).await?)
+}
+
+pub(crate) async fn get_max_video_id(pool: &sqlx::Pool<sqlx::Sqlite>) -> anyhow::Result<i64> {
+    let mut conn = pool.acquire().await?;
+
+    let (id,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(id), 0) FROM videos")
+        .fetch_one(&mut conn)
+        .await?;
+
+    Ok(id)
+}
+
+pub(crate) async fn get_video_count(pool: &sqlx::Pool<sqlx::Sqlite>) -> anyhow::Result<u32> {
+    let mut conn = pool.acquire().await?;
+
+    let (count,): (u32,) =
+        sqlx::query_as("SELECT COUNT(*) FROM videos WHERE unavailable_at IS NULL")
+            .fetch_one(&mut conn)
+            .await?;
+
+    Ok(count)
+}
+
+/// A page of all the videos in insertion order, which doesn't move as new videos come in.
+pub(crate) async fn get_videos_by_id(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    limit: u32,
+    offset: u32,
+) -> anyhow::Result<Vec<crate::brightcove::Video>> {
+    let mut conn = pool.acquire().await?;
+
+    let video_rows: Vec<VideoRow> = sqlx::query_as(&format!(
+        r#"
+            select {}
+            from videos
+            where unavailable_at IS NULL
+            ORDER BY id LIMIT ? OFFSET ?
+        "#,
+        VIDEO_COLUMNS
+    ))
     .bind(limit)
     .bind(offset)
     .fetch_all(&mut conn)
-    .await
-    .unwrap();
+    .await?;
 
-    let videos: Vec<crate::brightcove::Video> = video_rows.iter().map(|v| v.into()).collect();
+    Ok(video_rows.iter().map(|v| v.into()).collect())
+}
+
+/// Videos of the `data` day (`YYYY/MM/DD`).
+pub(crate) async fn get_videos_by_data(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    data: &str,
+) -> anyhow::Result<Vec<crate::brightcove::Video>> {
+    let mut conn = pool.acquire().await?;
 
-    crate::brightcove::PlayerResponse { count, videos }
+    let video_rows: Vec<VideoRow> = sqlx::query_as(&format!(
+        r#"
+            select {}
+            from videos
+            where data = ? AND unavailable_at IS NULL
+            ORDER BY ippodromo, bc_video_id
+        "#,
+        VIDEO_COLUMNS
+    ))
+    .bind(data)
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(video_rows.iter().map(|v| v.into()).collect())
 }
 
-pub(crate) async fn get_video(
+/// Days with videos, latest first, optionally between the `from` and `to` days (`YYYY/MM/DD`,
+/// both included).
+pub(crate) async fn get_racedays(
     pool: &sqlx::Pool<sqlx::Sqlite>,
-    video_id: &str,
-) -> crate::brightcove::Video {
-    let mut conn = pool.acquire().await.unwrap();
-    let video_row: VideoRow = sqlx::query_as(
+    from: Option<&str>,
+    to: Option<&str>,
+) -> anyhow::Result<Vec<crate::racedays::Raceday>> {
+    let mut conn = pool.acquire().await?;
+
+    let rows: Vec<(String, u32)> = sqlx::query_as(
         r#"
             select
-                name,
-                thumbnail,
-                numero_corsa,
                 data,
-                tipologia,
-                cavalli,
-                fantini,
-                primo,
-                secondo,
-                terzo,
-                ippodromo,
-                video_views,
-                bc_video_id
+                COUNT(*)
             from videos
-            where bc_video_id = ?
+            where (? IS NULL OR data >= ?) AND (? IS NULL OR data <= ?) AND unavailable_at IS NULL
+            GROUP BY data
+            ORDER BY data DESC
         "#,
     )
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to)
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(data, video_count)| crate::racedays::Raceday {
+            date: crate::racedays::from_data(&data),
+            video_count,
+        })
+        .collect())
+}
+
+pub(crate) async fn get_video(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    video_id: &str,
+) -> anyhow::Result<Option<crate::brightcove::Video>> {
+    let mut conn = pool.acquire().await?;
+    let video_row: Option<VideoRow> = sqlx::query_as(&format!(
+        r#"
+            select {}
+            from videos
+            where bc_video_id = ? AND unavailable_at IS NULL
+        "#,
+        VIDEO_COLUMNS
+    ))
     .bind(video_id)
-    .fetch_one(&mut conn)
-    .await
-    .unwrap();
+    .fetch_optional(&mut conn)
+    .await?;
 
-    let video: crate::brightcove::Video = video_row.into();
-    video
+    Ok(video_row.map(|v| v.into()))
 }
 
-/*
-pub(crate) async fn get_all_video_ids(
-    conn: &mut sqlx::pool::PoolConnection<sqlx::Sqlite>,
-) -> anyhow::Result<Vec<String>> {
-    #[derive(Debug, Clone, sqlx::FromRow)]
-    struct VideoId {
-        bc_video_id: String,
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::FromRow)]
+pub struct PlaylistRow {
+    #[serde(rename = "id")]
+    pub bc_playlist_id: String,
+    pub name: String,
+    pub description: Option<String>,
+    /// videos of the playlist that are in the db and available
+    pub video_count: u32,
+}
+
+/// Replaces the playlist and its videos in one transaction.
+pub(crate) async fn save_playlist(
+    conn: &mut sqlx::SqliteConnection,
+    playlist: &crate::brightcove::Playlist,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO playlists (name, description, bc_playlist_id)
+        VALUES (?, ?, ?)
+        ON CONFLICT (bc_playlist_id)
+        DO UPDATE SET name = excluded.name, description = excluded.description
+        "#,
+    )
+    .bind(&playlist.name)
+    .bind(&playlist.description)
+    .bind(&playlist.id)
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query("DELETE FROM playlist_videos WHERE bc_playlist_id = ?")
+        .bind(&playlist.id)
+        .execute(&mut tx)
+        .await?;
+
+    for (position, video) in playlist.videos.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO playlist_videos (bc_playlist_id, bc_video_id, position) VALUES (?, ?, ?)",
+        )
+        .bind(&playlist.id)
+        .bind(&video.id)
+        .bind(position as u32)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    log::debug!(target:"db", "saved playlist: {} - {} videos", &playlist.id, playlist.videos.len());
+
+    Ok(())
+}
+
+/// Removes the playlists that are no longer in Brightcove.
+pub(crate) async fn delete_playlists_except(
+    conn: &mut sqlx::SqliteConnection,
+    bc_playlist_ids: &[String],
+) -> anyhow::Result<u64> {
+    let keep = vec!["?"; bc_playlist_ids.len()].join(", ");
+    let mut tx = conn.begin().await?;
+    let mut rows_affected = 0;
+
+    for table in ["playlist_videos", "playlists"] {
+        let query_str = format!(
+            "DELETE FROM {} WHERE bc_playlist_id NOT IN ({})",
+            table, keep
+        );
+
+        let mut query = sqlx::query(&query_str);
+        for bc_playlist_id in bc_playlist_ids {
+            query = query.bind(bc_playlist_id);
+        }
+        // playlists comes last, that's the count returned
+        rows_affected = query.execute(&mut tx).await?.rows_affected();
+    }
+
+    tx.commit().await?;
+
+    Ok(rows_affected)
+}
+
+pub(crate) async fn get_playlists(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> anyhow::Result<crate::brightcove::PlaylistsResponse> {
+    let mut conn = pool.acquire().await?;
+
+    let playlists: Vec<PlaylistRow> = sqlx::query_as(
+        r#"
+            select
+                playlists.bc_playlist_id,
+                playlists.name,
+                playlists.description,
+                COUNT(videos.id) as video_count
+            from playlists
+            LEFT JOIN playlist_videos ON playlist_videos.bc_playlist_id = playlists.bc_playlist_id
+            LEFT JOIN videos ON videos.bc_video_id = playlist_videos.bc_video_id
+                AND videos.unavailable_at IS NULL
+            GROUP BY playlists.bc_playlist_id
+            ORDER BY playlists.name
+        "#,
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(crate::brightcove::PlaylistsResponse {
+        count: playlists.len() as u32,
+        playlists,
+    })
+}
+
+/// The playlist with its videos in playlist order. Videos not synced yet, or unavailable, are
+/// left out.
+pub(crate) async fn get_playlist(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    bc_playlist_id: &str,
+) -> anyhow::Result<Option<crate::brightcove::PlaylistResponse>> {
+    let mut conn = pool.acquire().await?;
+
+    let playlist: Option<(String, Option<String>)> =
+        sqlx::query_as("SELECT name, description FROM playlists WHERE bc_playlist_id = ?")
+            .bind(bc_playlist_id)
+            .fetch_optional(&mut conn)
+            .await?;
+
+    let (name, description) = match playlist {
+        Some(playlist) => playlist,
+        None => return Ok(None),
+    };
+
+    let video_rows: Vec<VideoRow> = sqlx::query_as(&format!(
+        r#"
+            select {}
+            from playlist_videos
+            JOIN videos USING (bc_video_id)
+            where playlist_videos.bc_playlist_id = ? AND videos.unavailable_at IS NULL
+            ORDER BY playlist_videos.position
+        "#,
+        VIDEO_COLUMNS
+    ))
+    .bind(bc_playlist_id)
+    .fetch_all(&mut conn)
+    .await?;
+
+    let videos: Vec<crate::brightcove::Video> = video_rows.iter().map(|v| v.into()).collect();
+
+    Ok(Some(crate::brightcove::PlaylistResponse {
+        id: bc_playlist_id.to_string(),
+        name,
+        description,
+        count: videos.len() as u32,
+        videos,
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, sqlx::FromRow)]
+pub struct WebhookSubscriptionRow {
+    pub id: i64,
+    pub url: String,
+    /// comma separated
+    pub events: String,
+    pub active: bool,
+    pub created_at: i64,
+}
+
+/// Returns the id of the subscription.
+pub(crate) async fn save_webhook_subscription(
+    conn: &mut sqlx::SqliteConnection,
+    url: &str,
+    secret: &str,
+    events: &str,
+    now: i64,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query(
+        "INSERT INTO webhook_subscriptions (url, secret, events, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(url)
+    .bind(secret)
+    .bind(events)
+    .bind(now)
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+/// Every subscription, deactivated ones included, without their secrets.
+pub(crate) async fn get_webhook_subscriptions(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> anyhow::Result<Vec<WebhookSubscriptionRow>> {
+    let subscriptions = sqlx::query_as(
+        r#"
+            select id, url, events, active, created_at
+            from webhook_subscriptions
+            ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(subscriptions)
+}
+
+/// Stops the deliveries to the subscription, its pending ones included. Returns false if it
+/// doesn't exist or was already deactivated.
+pub(crate) async fn deactivate_webhook_subscription(
+    conn: &mut sqlx::SqliteConnection,
+    id: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected =
+        sqlx::query("UPDATE webhook_subscriptions SET active = 0 WHERE id = ? AND active")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+
+    if rows_affected > 0 {
+        sqlx::query(
+            "UPDATE webhook_deliveries SET status = 'failed', last_error = 'unsubscribed' WHERE subscription_id = ? AND status = 'pending'",
+        )
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(rows_affected > 0)
+}
+
+/// Queues a delivery of `payload` to every active subscription to `event`.
+pub(crate) async fn enqueue_webhook_deliveries(
+    conn: &mut sqlx::SqliteConnection,
+    event: &str,
+    payload: &str,
+    now: i64,
+) -> anyhow::Result<u64> {
+    let rows_affected = sqlx::query(
+        r#"
+        INSERT INTO webhook_deliveries (subscription_id, event, payload, next_attempt_at, created_at)
+        SELECT id, ?, ?, ?, ?
+        FROM webhook_subscriptions
+        WHERE active AND (',' || events || ',') LIKE ('%,' || ? || ',%')
+        "#,
+    )
+    .bind(event)
+    .bind(payload)
+    .bind(now)
+    .bind(now)
+    .bind(event)
+    .execute(conn)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DueDelivery {
+    pub id: i64,
+    pub event: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub url: String,
+    pub secret: String,
+}
+
+/// Pending deliveries whose attempt is due, the oldest of each subscription in turn so a
+/// subscription with a backlog doesn't hold back the others.
+pub(crate) async fn get_due_webhook_deliveries(
+    conn: &mut sqlx::SqliteConnection,
+    now: i64,
+    limit: u32,
+) -> anyhow::Result<Vec<DueDelivery>> {
+    let deliveries = sqlx::query_as(
+        r#"
+            select id, event, payload, attempts, url, secret
+            from (
+                select
+                    webhook_deliveries.id,
+                    webhook_deliveries.event,
+                    webhook_deliveries.payload,
+                    webhook_deliveries.attempts,
+                    webhook_subscriptions.url,
+                    webhook_subscriptions.secret,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY webhook_deliveries.subscription_id
+                        ORDER BY webhook_deliveries.id
+                    ) AS position
+                from webhook_deliveries
+                JOIN webhook_subscriptions ON webhook_subscriptions.id = webhook_deliveries.subscription_id
+                where webhook_deliveries.status = 'pending' AND webhook_deliveries.next_attempt_at <= ?
+            )
+            ORDER BY position, id
+            LIMIT ?
+        "#,
+    )
+    .bind(now)
+    .bind(limit)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(deliveries)
+}
+
+/// Records an attempt. With `next_attempt_at` the delivery is retried then, without it the
+/// delivery is done: `delivered` if `delivered`, `failed` otherwise.
+pub(crate) async fn save_webhook_attempt(
+    conn: &mut sqlx::SqliteConnection,
+    id: i64,
+    delivered: bool,
+    status_code: Option<u16>,
+    error: Option<&str>,
+    next_attempt_at: Option<i64>,
+    now: i64,
+) -> anyhow::Result<()> {
+    let status = match (delivered, next_attempt_at) {
+        (true, _) => "delivered",
+        (false, Some(_)) => "pending",
+        (false, None) => "failed",
+    };
+
+    sqlx::query(
+        r#"
+        UPDATE webhook_deliveries SET
+            status = ?,
+            attempts = attempts + 1,
+            next_attempt_at = COALESCE(?, next_attempt_at),
+            last_status_code = ?,
+            last_error = ?,
+            delivered_at = CASE WHEN ? THEN ? ELSE delivered_at END
+        WHERE id = ?
+        "#,
+    )
+    .bind(status)
+    .bind(next_attempt_at)
+    .bind(status_code)
+    .bind(error)
+    .bind(delivered)
+    .bind(now)
+    .bind(id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Drops the delivered and failed deliveries created before `before`, returns how many.
+pub(crate) async fn delete_webhook_deliveries_before(
+    conn: &mut sqlx::SqliteConnection,
+    before: i64,
+) -> anyhow::Result<u64> {
+    let rows_affected =
+        sqlx::query("DELETE FROM webhook_deliveries WHERE created_at < ? AND status != 'pending'")
+            .bind(before)
+            .execute(conn)
+            .await?
+            .rows_affected();
+
+    Ok(rows_affected)
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, sqlx::FromRow)]
+pub struct WebhookDeliveryRow {
+    pub id: i64,
+    pub event: String,
+    /// `pending`, `delivered` or `failed`
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub delivered_at: Option<i64>,
+}
+
+/// The last `limit` deliveries of a subscription, newest first, without their payloads.
+pub(crate) async fn get_webhook_deliveries(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    subscription_id: i64,
+    status: Option<&str>,
+    limit: u32,
+) -> anyhow::Result<Vec<WebhookDeliveryRow>> {
+    let deliveries = sqlx::query_as(
+        r#"
+            select
+                id,
+                event,
+                status,
+                attempts,
+                next_attempt_at,
+                last_status_code,
+                last_error,
+                created_at,
+                delivered_at
+            from webhook_deliveries
+            where subscription_id = ? AND (? IS NULL OR status = ?)
+            ORDER BY id DESC
+            LIMIT ?
+        "#,
+    )
+    .bind(subscription_id)
+    .bind(status)
+    .bind(status)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(deliveries)
+}
+
+/// Stored views for the `bc_video_ids` that are in the db, unknown and unavailable ids are
+/// left out.
+pub(crate) async fn get_video_views(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    bc_video_ids: &[String],
+) -> anyhow::Result<Vec<crate::brightcove::analytics::Video>> {
+    if bc_video_ids.is_empty() {
+        return Ok(vec![]);
     }
 
-    let video_ids: Vec<VideoId> = sqlx::query_as(
+    let mut conn = pool.acquire().await?;
+
+    let query_str = format!(
         r#"
             select
-                bc_video_id as "String"
+                bc_video_id,
+                video_views
             from videos
+            where bc_video_id IN ({}) AND unavailable_at IS NULL
+        "#,
+        vec!["?"; bc_video_ids.len()].join(", ")
+    );
+
+    let mut query = sqlx::query_as::<_, (String, u32)>(&query_str);
+    for bc_video_id in bc_video_ids {
+        query = query.bind(bc_video_id);
+    }
+
+    let rows = query.fetch_all(&mut conn).await?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(bc_video_id, video_views)| crate::brightcove::analytics::Video {
+                video: Some(bc_video_id),
+                video_view: video_views,
+            },
+        )
+        .collect())
+}
+
+pub(crate) async fn save_video_analytics(
+    conn: &mut sqlx::SqliteConnection,
+    dimension: Dimension,
+    items: &[BreakdownItem],
+) -> anyhow::Result<()> {
+    for item in items {
+        let (bc_video_id, value) = match (&item.video, &item.value) {
+            (Some(bc_video_id), Some(value)) => (bc_video_id, value),
+            _ => continue,
+        };
+
+        sqlx::query(
+            r#"
+        INSERT INTO video_analytics (bc_video_id, dimension, value, video_view)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (bc_video_id, dimension, value)
+        DO UPDATE SET video_view = excluded.video_view
+        "#,
+        )
+        .bind(bc_video_id)
+        .bind(dimension.as_str())
+        .bind(value)
+        .bind(item.video_view)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    log::debug!(target:"db", "saved {} {} analytics rows", items.len(), dimension.as_str());
+
+    Ok(())
+}
+
+/// Sums the views of `bc_video_ids` (or of every video, if empty) grouped by the values
+/// of `dimension`.
+pub(crate) async fn get_video_analytics(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    dimension: Dimension,
+    bc_video_ids: &[String],
+) -> anyhow::Result<SummaryResponse> {
+    let mut conn = pool.acquire().await?;
+
+    let video_filter = if bc_video_ids.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "AND bc_video_id IN ({})",
+            vec!["?"; bc_video_ids.len()].join(", ")
+        )
+    };
+
+    let query_str = format!(
+        r#"
+            select
+                value,
+                SUM(video_view) as video_view
+            from video_analytics
+            where dimension = ? {}
+            GROUP BY value
+            ORDER BY value
+        "#,
+        video_filter
+    );
+
+    let mut query = sqlx::query_as::<_, SummaryItem>(&query_str).bind(dimension.as_str());
+    for bc_video_id in bc_video_ids {
+        query = query.bind(bc_video_id);
+    }
+
+    let items = query.fetch_all(&mut conn).await?;
+
+    Ok(SummaryResponse {
+        dimension,
+        item_count: items.len() as u32,
+        items,
+    })
+}
+
+/// Brightcove ids of every video in the db, unavailable ones included.
+pub(crate) async fn get_all_video_ids(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> anyhow::Result<Vec<String>> {
+    let video_ids: Vec<(String,)> = sqlx::query_as("SELECT bc_video_id FROM videos")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(video_ids.into_iter().map(|v| v.0).collect())
+}
+
+/// What a pass of a sync did to the videos.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SyncCounts {
+    pub fetched: u64,
+    pub inserted: u64,
+    pub updated: u64,
+    /// made unavailable, the rows are kept
+    pub deleted: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, sqlx::FromRow)]
+pub struct SyncRun {
+    pub id: i64,
+    pub task: String,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub videos_fetched: i64,
+    pub videos_inserted: i64,
+    pub videos_updated: i64,
+    pub videos_deleted: i64,
+    pub videos_failed: i64,
+    pub error: Option<String>,
+}
+
+/// Records the start of a pass of `task`, returns the id `finish_sync_run` takes.
+pub(crate) async fn start_sync_run(
+    conn: &mut sqlx::SqliteConnection,
+    task: &str,
+    started_at: i64,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query("INSERT INTO sync_runs (task, started_at) VALUES (?, ?)")
+        .bind(task)
+        .bind(started_at)
+        .execute(conn)
+        .await?
+        .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub(crate) async fn finish_sync_run(
+    conn: &mut sqlx::SqliteConnection,
+    id: i64,
+    counts: &SyncCounts,
+    error: Option<&str>,
+    finished_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE sync_runs SET
+            finished_at = ?,
+            videos_fetched = ?,
+            videos_inserted = ?,
+            videos_updated = ?,
+            videos_deleted = ?,
+            videos_failed = ?,
+            error = ?
+        WHERE id = ?
         "#,
     )
-    .fetch_all(&mut *conn)
-    .await
-    .unwrap();
+    .bind(finished_at)
+    .bind(counts.fetched as i64)
+    .bind(counts.inserted as i64)
+    .bind(counts.updated as i64)
+    .bind(counts.deleted as i64)
+    .bind(counts.failed as i64)
+    .bind(error)
+    .bind(id)
+    .execute(conn)
+    .await?;
 
-    Ok(video_ids.iter().map(|v| v.bc_video_id.clone()).collect())
+    Ok(())
+}
+
+/// Drops the runs started before `before`, returns how many.
+pub(crate) async fn delete_sync_runs_before(
+    conn: &mut sqlx::SqliteConnection,
+    before: i64,
+) -> anyhow::Result<u64> {
+    let rows_affected = sqlx::query("DELETE FROM sync_runs WHERE started_at < ?")
+        .bind(before)
+        .execute(conn)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// The last `limit` sync runs, newest first.
+pub(crate) async fn get_sync_runs(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    limit: u32,
+) -> anyhow::Result<Vec<SyncRun>> {
+    let runs = sqlx::query_as(
+        r#"
+            select
+                id,
+                task,
+                started_at,
+                finished_at,
+                videos_fetched,
+                videos_inserted,
+                videos_updated,
+                videos_deleted,
+                videos_failed,
+                error
+            from sync_runs
+            ORDER BY id DESC
+            LIMIT ?
+        "#,
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(runs)
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, sqlx::FromRow)]
+pub struct ApiKeyRow {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    /// comma separated
+    pub scopes: String,
+    /// requests per minute
+    pub rate_limit: u32,
+    pub created_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+pub(crate) async fn save_api_key(
+    conn: &mut sqlx::SqliteConnection,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    scopes: &str,
+    rate_limit: u32,
+    now: i64,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query(
+        r#"
+        INSERT INTO api_keys (name, prefix, key_hash, scopes, rate_limit, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(name)
+    .bind(prefix)
+    .bind(key_hash)
+    .bind(scopes)
+    .bind(rate_limit)
+    .bind(now)
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+/// The key with `key_hash`, unless it's revoked.
+pub(crate) async fn get_api_key_by_hash(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    key_hash: &str,
+) -> anyhow::Result<Option<ApiKeyRow>> {
+    let key = sqlx::query_as(
+        r#"
+            select id, name, prefix, scopes, rate_limit, created_at, revoked_at
+            from api_keys
+            where key_hash = ? AND revoked_at IS NULL
+        "#,
+    )
+    .bind(key_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(key)
+}
+
+pub(crate) async fn get_api_keys(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> anyhow::Result<Vec<ApiKeyRow>> {
+    let keys = sqlx::query_as(
+        r#"
+            select id, name, prefix, scopes, rate_limit, created_at, revoked_at
+            from api_keys
+            ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(keys)
+}
+
+/// Returns false if there's no such key, or it was already revoked.
+pub(crate) async fn revoke_api_key(
+    conn: &mut sqlx::SqliteConnection,
+    id: i64,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected =
+        sqlx::query("UPDATE api_keys SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .execute(conn)
+            .await?
+            .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+#[test]
+fn validate_video_filter_date() {
+    let filter = |date: Option<&str>| VideoFilter {
+        date: date.map(str::to_string),
+        ..Default::default()
+    };
+
+    assert!(filter(None).is_valid());
+    assert!(filter(Some("2022-03-20")).is_valid());
+    assert!(!filter(Some("2022/03/20")).is_valid());
+    assert!(!filter(Some("20-03-2022")).is_valid());
+    assert!(!filter(Some("")).is_valid());
+}
+
+#[tokio::test]
+async fn save_videos_all_or_none() {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+
+    let video = |bc_video_id: &str| VideoRow {
+        name: "Corsa 3".to_string(),
+        thumbnail: String::new(),
+        numero_corsa: "3".to_string(),
+        data: "2022/03/20".to_string(),
+        tipologia: None,
+        cavalli: None,
+        fantini: None,
+        primo: None,
+        secondo: None,
+        terzo: None,
+        ippodromo: "FIRENZE".to_string(),
+        video_views: 0,
+        bc_video_id: bc_video_id.to_string(),
+        poster: None,
+        duration: None,
+        sources: None,
+        text_tracks: None,
+    };
+
+    let mut conn = pool.acquire().await.unwrap();
+    let mut tx = conn.begin().await.unwrap();
+    save_videos(&mut tx, &vec![video("1")]).await.unwrap();
+    // the second "2" breaks the unique bc_video_id, the first isn't kept either
+    assert!(save_videos(&mut tx, &vec![video("2"), video("2")])
+        .await
+        .is_err());
+    tx.commit().await.unwrap();
+
+    let ids: Vec<(String,)> = sqlx::query_as("SELECT bc_video_id FROM videos")
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(ids, vec![("1".to_string(),)]);
 }
-*/
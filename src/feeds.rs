@@ -0,0 +1,389 @@
+use axum::{
+    extract::{Extension, Query},
+    response::Response,
+    routing::get,
+    Router,
+};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use sqlx::sqlite::SqlitePool;
+use std::sync::Arc;
+
+use crate::api_keys::Access;
+use crate::brightcove::{Delivery, Video};
+use crate::cache::VideoCache;
+use crate::db::{self, VideoFilter};
+use crate::http_cache;
+use crate::site::{
+    content_source, escape_xml, http_date, rfc3339_date, video_description, VideoPages,
+};
+
+const FEED_ITEMS: u32 = 50;
+const FEED_MAX_AGE_IN_S: u32 = 300;
+
+/// Feeds of the latest videos. `ippodromo`, `tipologia` and `date` narrow them like the
+/// video listing, e.g. `/feeds/videos.rss?ippodromo=FIRENZE`. Without `VIDEO_PAGE_URL` there's
+/// nothing to link the items to, every feed answers `501`. `Last-Modified` is the change stamp
+/// of `video_cache`, the last time the syncs changed the videos.
+pub fn router(pool: SqlitePool, anonymous: Access, video_cache: Arc<VideoCache>) -> Router {
+    let video_pages = VideoPages::from_env();
+    if video_pages.is_none() {
+        log::info!("VIDEO_PAGE_URL not set, feeds disabled");
+    }
+
+    Router::new()
+        .route("/videos.rss", get(videos_rss))
+        .route("/videos.atom", get(videos_atom))
+        .route("/videos.mrss", get(videos_mrss))
+        .layer(Extension(pool))
+        .layer(Extension(anonymous))
+        .layer(Extension(video_pages))
+        .layer(Extension(video_cache))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Rss,
+    MediaRss,
+    Atom,
+}
+
+async fn videos_rss(
+    pool: Extension<SqlitePool>,
+    access: Extension<Access>,
+    video_pages: Extension<Option<VideoPages>>,
+    video_cache: Extension<Arc<VideoCache>>,
+    filter: Query<VideoFilter>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    feed(
+        Format::Rss,
+        &pool,
+        &access,
+        &video_pages,
+        &video_cache,
+        &filter,
+        &headers,
+    )
+    .await
+}
+
+async fn videos_mrss(
+    pool: Extension<SqlitePool>,
+    access: Extension<Access>,
+    video_pages: Extension<Option<VideoPages>>,
+    video_cache: Extension<Arc<VideoCache>>,
+    filter: Query<VideoFilter>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    feed(
+        Format::MediaRss,
+        &pool,
+        &access,
+        &video_pages,
+        &video_cache,
+        &filter,
+        &headers,
+    )
+    .await
+}
+
+async fn videos_atom(
+    pool: Extension<SqlitePool>,
+    access: Extension<Access>,
+    video_pages: Extension<Option<VideoPages>>,
+    video_cache: Extension<Arc<VideoCache>>,
+    filter: Query<VideoFilter>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    feed(
+        Format::Atom,
+        &pool,
+        &access,
+        &video_pages,
+        &video_cache,
+        &filter,
+        &headers,
+    )
+    .await
+}
+
+/// The latest videos, as anonymous requests see them, in `format`. `400` if the filter is
+/// invalid, `304` if the client's copy is still fresh.
+async fn feed(
+    format: Format,
+    pool: &SqlitePool,
+    access: &Access,
+    video_pages: &Option<VideoPages>,
+    video_cache: &VideoCache,
+    filter: &VideoFilter,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let video_pages = video_pages.as_ref().ok_or(StatusCode::NOT_IMPLEMENTED)?;
+    if !filter.is_valid() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // read before the videos: a change during the read labels them older, never newer
+    let changed_at = video_cache.changed_at();
+    let mut videos = db::get_videos(pool, &FEED_ITEMS, &0, filter)
+        .await
+        .map_err(|e| {
+            log::error!(target: "feeds", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .videos;
+    access.redact(videos.iter_mut());
+
+    let (content_type, body) = match format {
+        Format::Rss => (
+            "application/rss+xml",
+            rss(&videos, filter, video_pages, false),
+        ),
+        Format::MediaRss => (
+            "application/rss+xml",
+            rss(&videos, filter, video_pages, true),
+        ),
+        Format::Atom => ("application/atom+xml", atom(&videos, filter, video_pages)),
+    };
+
+    Ok(http_cache::conditional(
+        headers,
+        body.into_bytes(),
+        HeaderValue::from_str(&format!("{}; charset=utf-8", content_type)).unwrap(),
+        changed_at,
+        &HeaderValue::from_str(&format!("public, max-age={}", FEED_MAX_AGE_IN_S)).unwrap(),
+    ))
+}
+
+fn feed_title(filter: &VideoFilter) -> String {
+    let mut title = "Race videos".to_string();
+
+    for part in [&filter.ippodromo, &filter.tipologia, &filter.date]
+        .into_iter()
+        .flatten()
+    {
+        title.push_str(" - ");
+        title.push_str(part);
+    }
+
+    title
+}
+
+/// RSS 2.0, with `media` Media RSS elements if `media_rss`.
+fn rss(
+    videos: &[Video],
+    filter: &VideoFilter,
+    video_pages: &VideoPages,
+    media_rss: bool,
+) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push('\n');
+    if media_rss {
+        xml.push_str(r#"<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/">"#);
+    } else {
+        xml.push_str(r#"<rss version="2.0">"#);
+    }
+    xml.push_str("<channel>");
+    xml.push_str(&format!(
+        "<title>{}</title>",
+        escape_xml(&feed_title(filter))
+    ));
+    xml.push_str(&format!(
+        "<link>{}</link>",
+        escape_xml(video_pages.site_url())
+    ));
+    xml.push_str(&format!(
+        "<description>{}</description>",
+        escape_xml(&feed_title(filter))
+    ));
+
+    for video in videos {
+        let link = video_pages.url(&video.id);
+        let description = video_description(video);
+
+        xml.push_str("<item>");
+        xml.push_str(&format!("<title>{}</title>", escape_xml(&video.name)));
+        xml.push_str(&format!("<link>{}</link>", escape_xml(&link)));
+        xml.push_str(&format!(
+            r#"<guid isPermaLink="false">{}</guid>"#,
+            escape_xml(&video.id)
+        ));
+        xml.push_str(&format!(
+            "<description>{}</description>",
+            escape_xml(&description)
+        ));
+        if let Some(pub_date) = http_date(&video.custom_fields.data) {
+            xml.push_str(&format!("<pubDate>{}</pubDate>", pub_date));
+        }
+        if let Some(tipologia) = &video.custom_fields.tipologia {
+            xml.push_str(&format!("<category>{}</category>", escape_xml(tipologia)));
+        }
+
+        if media_rss {
+            xml.push_str(&media_content(video));
+            xml.push_str(&format!(
+                "<media:title>{}</media:title>",
+                escape_xml(&video.name)
+            ));
+            xml.push_str(&format!(
+                "<media:description>{}</media:description>",
+                escape_xml(&description)
+            ));
+            xml.push_str(&format!(
+                r#"<media:thumbnail url="{}"/>"#,
+                escape_xml(&video.thumbnail)
+            ));
+        } else if let Some((src, size)) = enclosure(video) {
+            xml.push_str(&format!(
+                r#"<enclosure url="{}" length="{}" type="video/mp4"/>"#,
+                escape_xml(src),
+                size
+            ));
+        }
+
+        xml.push_str("</item>");
+    }
+
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+/// The highest resolution mp4 rendition with its size in bytes, which RSS and Atom want with
+/// an enclosure. Videos without one go without an enclosure.
+fn enclosure(video: &Video) -> Option<(&str, u64)> {
+    video
+        .sources
+        .iter()
+        .filter(|s| Delivery::Mp4.matches(s))
+        .filter_map(|s| Some((s.src.as_deref()?, s.size?, s.height.unwrap_or(0))))
+        .max_by_key(|(_, _, height)| *height)
+        .map(|(src, size, _)| (src, size))
+}
+
+fn media_content(video: &Video) -> String {
+    match content_source(video) {
+        Some(source) => {
+            let mut content = format!(
+                r#"<media:content url="{}" medium="video""#,
+                escape_xml(source.src.as_deref().unwrap_or_default())
+            );
+            if let Some(mime_type) = &source.mime_type {
+                content.push_str(&format!(r#" type="{}""#, escape_xml(mime_type)));
+            } else if Delivery::Mp4.matches(source) {
+                content.push_str(r#" type="video/mp4""#);
+            }
+            if let Some(duration) = video.duration {
+                content.push_str(&format!(r#" duration="{}""#, duration / 1000));
+            }
+            if let (Some(width), Some(height)) = (source.width, source.height) {
+                content.push_str(&format!(r#" width="{}" height="{}""#, width, height));
+            }
+            content.push_str("/>");
+            content
+        }
+        None => String::new(),
+    }
+}
+
+fn atom(videos: &[Video], filter: &VideoFilter, video_pages: &VideoPages) -> String {
+    let updated = videos
+        .iter()
+        .map(|v| &v.custom_fields.data)
+        .max()
+        .and_then(|data| rfc3339_date(data))
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push('\n');
+    xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    xml.push_str(&format!(
+        "<title>{}</title>",
+        escape_xml(&feed_title(filter))
+    ));
+    xml.push_str(&format!(
+        "<id>{}</id>",
+        escape_xml(&format!("{}/feeds/videos.atom", video_pages.site_url()))
+    ));
+    xml.push_str(&format!(
+        r#"<link href="{}"/>"#,
+        escape_xml(video_pages.site_url())
+    ));
+    xml.push_str(&format!("<updated>{}</updated>", updated));
+
+    for video in videos {
+        let link = video_pages.url(&video.id);
+        let date = rfc3339_date(&video.custom_fields.data).unwrap_or_else(|| updated.clone());
+
+        xml.push_str("<entry>");
+        xml.push_str(&format!("<title>{}</title>", escape_xml(&video.name)));
+        xml.push_str(&format!(r#"<link href="{}"/>"#, escape_xml(&link)));
+        xml.push_str(&format!("<id>{}</id>", escape_xml(&link)));
+        xml.push_str(&format!("<updated>{}</updated>", date));
+        xml.push_str(&format!(
+            "<author><name>{}</name></author>",
+            escape_xml(&video.custom_fields.ippodromo)
+        ));
+        xml.push_str(&format!(
+            "<summary>{}</summary>",
+            escape_xml(&video_description(video))
+        ));
+        if let Some((src, size)) = enclosure(video) {
+            xml.push_str(&format!(
+                r#"<link rel="enclosure" type="video/mp4" length="{}" href="{}"/>"#,
+                size,
+                escape_xml(src)
+            ));
+        }
+        xml.push_str("</entry>");
+    }
+
+    xml.push_str("</feed>");
+    xml
+}
+
+#[test]
+fn media_rss_feed() {
+    let video: Video = serde_json::from_str(
+        r#"{"id":"6301819598001","name":"Corsa 3 & premio","thumbnail":"https://cf-images.example.com/thumb.jpg","video_views":null,"poster":null,"duration":95000,
+        "custom_fields":{"numero_corsa":"3","data":"2022/03/20","tipologia":"TROTTO","cavalli":null,"fantini":null,"primo":"ALFA","secondo":null,"terzo":null,"ippodromo":"FIRENZE"},
+        "sources":[{"src":"https://example.com/master.m3u8","type":"application/x-mpegURL"},{"src":"https://example.com/360.mp4","container":"MP4","width":640,"height":360,"size":4200000},{"src":"https://example.com/720.mp4","container":"MP4","width":1280,"height":720}]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        video_description(&video),
+        "Ippodromo: FIRENZE\nData: 2022/03/20\nCorsa: 3\nTipologia: TROTTO\nPrimo: ALFA"
+    );
+    assert_eq!(
+        media_content(&video),
+        r#"<media:content url="https://example.com/720.mp4" medium="video" type="video/mp4" duration="95" width="1280" height="720"/>"#
+    );
+
+    let filter = VideoFilter {
+        ippodromo: Some("FIRENZE".to_string()),
+        ..Default::default()
+    };
+    let video_pages = VideoPages::new("https://www.example.com/video/{id}");
+    let xml = rss(std::slice::from_ref(&video), &filter, &video_pages, true);
+    assert!(xml.contains("<title>Race videos - FIRENZE</title>"));
+    assert!(xml.contains("<link>https://www.example.com/video/6301819598001</link>"));
+    assert!(xml.contains("<title>Corsa 3 &amp; premio</title>"));
+    assert!(xml.contains("<pubDate>Sun, 20 Mar 2022 00:00:00 GMT</pubDate>"));
+    assert!(xml.contains(r#"<media:thumbnail url="https://cf-images.example.com/thumb.jpg"/>"#));
+
+    // the 720p has no size, the enclosure is the 360p
+    let xml = rss(std::slice::from_ref(&video), &filter, &video_pages, false);
+    assert!(xml.contains(
+        r#"<enclosure url="https://example.com/360.mp4" length="4200000" type="video/mp4"/>"#
+    ));
+    let xml = atom(std::slice::from_ref(&video), &filter, &video_pages);
+    assert!(xml.contains(
+        r#"<link rel="enclosure" type="video/mp4" length="4200000" href="https://example.com/360.mp4"/>"#
+    ));
+
+    let hls_only = Video {
+        sources: video.sources[..1].to_vec(),
+        ..video
+    };
+    assert!(!rss(&[hls_only], &filter, &video_pages, false).contains("<enclosure"));
+}
@@ -0,0 +1,285 @@
+//! `/healthz`: the process is up. `/readyz`: it can serve fresh data, for the load balancer
+//! and on-call.
+
+use axum::{extract::Extension, response::Json, routing::get, Router};
+use http::StatusCode;
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{sync::Mutex, time};
+
+use crate::supervisor::{Supervisor, TaskState};
+use crate::writer::Writer;
+
+/// How late the `access_token` task can be on its interval, for the token to be fresh.
+const TOKEN_GRACE: Duration = Duration::from_secs(60);
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How old the token and the last successful video and views syncs can be for the proxy to
+/// be ready. `READYZ_ACCESS_TOKEN_MAX_AGE_IN_S`, `READYZ_SYNC_VIDEOS_MAX_AGE_IN_S` and
+/// `READYZ_SYNC_VIEWS_MAX_AGE_IN_S` override the defaults: the token refresh interval plus a
+/// minute, and three times the sync intervals.
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub access_token: Duration,
+    pub sync_videos: Duration,
+    pub sync_views: Duration,
+}
+
+impl Thresholds {
+    pub fn from_env(
+        access_token_interval: u64,
+        sync_video_interval: u64,
+        sync_views_interval: u64,
+    ) -> anyhow::Result<Self> {
+        let value =
+            |name: &str, default: u64| crate::env_var(name, default).map(Duration::from_secs);
+
+        Ok(Thresholds {
+            access_token: value(
+                "READYZ_ACCESS_TOKEN_MAX_AGE_IN_S",
+                access_token_interval + TOKEN_GRACE.as_secs(),
+            )?,
+            sync_videos: value("READYZ_SYNC_VIDEOS_MAX_AGE_IN_S", 3 * sync_video_interval)?,
+            sync_views: value("READYZ_SYNC_VIEWS_MAX_AGE_IN_S", 3 * sync_views_interval)?,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct HealthState {
+    pool: SqlitePool,
+    writer: Writer,
+    supervisor: Supervisor,
+    brightcove_access_token: Arc<Mutex<String>>,
+    thresholds: Thresholds,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// the task hasn't completed its first pass yet, but it's in time
+    Starting,
+    /// paused from `/admin` or by `serve --no-sync`, its data may be stale but on purpose
+    Paused,
+    Failing,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Component {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub components: BTreeMap<&'static str, Component>,
+}
+
+pub fn router(
+    pool: SqlitePool,
+    writer: Writer,
+    supervisor: Supervisor,
+    brightcove_access_token: Arc<Mutex<String>>,
+    thresholds: Thresholds,
+) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(Extension(HealthState {
+            pool,
+            writer,
+            supervisor,
+            brightcove_access_token,
+            thresholds,
+        }))
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(state: Extension<HealthState>) -> (StatusCode, Json<Readiness>) {
+    let now = crate::unix_time_in_ms();
+    let tasks = state.supervisor.states();
+    let task = |name: &str| tasks.get(name).cloned().unwrap_or_default();
+
+    let mut components = BTreeMap::new();
+
+    components.insert("database", database(&state.pool).await);
+    components.insert("db_writer", db_writer(&state.writer));
+
+    let token_present = !state.brightcove_access_token.lock().await.is_empty();
+    components.insert(
+        "brightcove_token",
+        brightcove_token(
+            token_present,
+            &task("access_token"),
+            state.thresholds.access_token,
+            now,
+        ),
+    );
+
+    components.insert(
+        "sync_videos",
+        sync(&task("sync_videos"), state.thresholds.sync_videos, now),
+    );
+    components.insert(
+        "sync_views",
+        sync(&task("sync_views"), state.thresholds.sync_views, now),
+    );
+
+    let readiness = Readiness {
+        ready: components.values().all(|c| c.status != Status::Failing),
+        components,
+    };
+
+    match readiness.ready {
+        true => (StatusCode::OK, Json(readiness)),
+        false => (StatusCode::SERVICE_UNAVAILABLE, Json(readiness)),
+    }
+}
+
+async fn database(pool: &SqlitePool) -> Component {
+    let ping = time::timeout(
+        DATABASE_TIMEOUT,
+        sqlx::query_as::<_, (i64,)>("SELECT 1").fetch_one(pool),
+    )
+    .await;
+
+    let error = match ping {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+
+    Component {
+        status: match error {
+            None => Status::Ok,
+            Some(_) => Status::Failing,
+        },
+        last_success_at: None,
+        error,
+    }
+}
+
+/// The writer task has to be running for the syncs to save anything.
+fn db_writer(writer: &Writer) -> Component {
+    let running = writer.is_running();
+
+    Component {
+        status: match running {
+            true => Status::Ok,
+            false => Status::Failing,
+        },
+        last_success_at: None,
+        error: (!running).then(|| "the db writer has stopped".to_string()),
+    }
+}
+
+/// The token is refreshed by the `access_token` task, it's fresh if the task refreshed it
+/// within `max_age`.
+fn brightcove_token(present: bool, task: &TaskState, max_age: Duration, now: i64) -> Component {
+    let fresh = task
+        .last_success_at
+        .map(|at| now - at <= max_age.as_millis() as i64)
+        .unwrap_or(false);
+
+    Component {
+        status: match present && fresh {
+            true => Status::Ok,
+            false => Status::Failing,
+        },
+        last_success_at: task.last_success_at,
+        error: task.last_error.clone(),
+    }
+}
+
+/// Ok if the task succeeded within `max_age`. A task yet to succeed is given `max_age` from
+/// its start, a paused one isn't checked.
+fn sync(task: &TaskState, max_age: Duration, now: i64) -> Component {
+    let within = |at: Option<i64>| {
+        at.map(|at| now - at <= max_age.as_millis() as i64)
+            .unwrap_or(false)
+    };
+
+    let status = match task.last_success_at {
+        _ if task.paused => Status::Paused,
+        Some(_) if within(task.last_success_at) => Status::Ok,
+        None if within(task.started_at) => Status::Starting,
+        _ => Status::Failing,
+    };
+
+    Component {
+        status,
+        last_success_at: task.last_success_at,
+        error: task.last_error.clone(),
+    }
+}
+
+#[test]
+fn sync_freshness() {
+    let now = 1_000_000_000;
+    let max_age = Duration::from_secs(60);
+    let task = |started_at: i64, last_success_at: Option<i64>| TaskState {
+        running: true,
+        started_at: Some(started_at),
+        last_success_at,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        sync(&task(now - 10_000, None), max_age, now).status,
+        Status::Starting
+    );
+    assert_eq!(
+        sync(&task(now - 120_000, None), max_age, now).status,
+        Status::Failing
+    );
+    assert_eq!(
+        sync(&task(now - 120_000, Some(now - 30_000)), max_age, now).status,
+        Status::Ok
+    );
+    assert_eq!(
+        sync(&task(now - 600_000, Some(now - 90_000)), max_age, now).status,
+        Status::Failing
+    );
+    assert_eq!(
+        sync(
+            &TaskState {
+                paused: true,
+                ..task(now - 600_000, None)
+            },
+            max_age,
+            now
+        )
+        .status,
+        Status::Paused
+    );
+
+    // refreshed every 10 minutes
+    let token_max_age = Duration::from_secs(600) + TOKEN_GRACE;
+    assert_eq!(
+        brightcove_token(true, &task(now, Some(now - 10_000)), token_max_age, now).status,
+        Status::Ok
+    );
+    assert_eq!(
+        brightcove_token(true, &task(now, Some(now - 620_000)), token_max_age, now).status,
+        Status::Ok
+    );
+    assert_eq!(
+        brightcove_token(true, &task(now, Some(now - 700_000)), token_max_age, now).status,
+        Status::Failing
+    );
+    assert_eq!(
+        brightcove_token(false, &task(now, Some(now)), token_max_age, now).status,
+        Status::Failing
+    );
+}
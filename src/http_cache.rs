@@ -0,0 +1,191 @@
+//! Conditional responses: `ETag` and `Last-Modified` validators, `304 Not Modified` and the
+//! `Cache-Control` of each route.
+
+use axum::response::{IntoResponse, Response};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::Serialize;
+
+use crate::site::{http_date_from_unix, parse_http_date};
+
+/// `Cache-Control` of the cacheable routes. Each can be overridden with its
+/// `CACHE_CONTROL_<ROUTE>` variable, e.g. `CACHE_CONTROL_VIDEOS=public, max-age=10`.
+#[derive(Debug, Clone)]
+pub struct CacheControl {
+    pub videos: HeaderValue,
+    pub video: HeaderValue,
+    pub racedays: HeaderValue,
+}
+
+impl CacheControl {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let value = |name: &str, default: &'static str| {
+            crate::env_var(name, HeaderValue::from_static(default))
+        };
+
+        Ok(CacheControl {
+            videos: value("CACHE_CONTROL_VIDEOS", "public, max-age=30")?,
+            video: value("CACHE_CONTROL_VIDEO", "public, max-age=60")?,
+            racedays: value("CACHE_CONTROL_RACEDAYS", "public, max-age=60")?,
+        })
+    }
+}
+
+/// Strong `ETag` of a response body: the quoted hex of its first 128 bits of SHA-256.
+pub fn etag(body: &[u8]) -> String {
+    let hash: String = openssl::sha::sha256(body)[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    format!("\"{}\"", hash)
+}
+
+/// Whether the client already has the representation with `etag`, last modified at
+/// `last_modified` (unix time in seconds). `If-None-Match` wins over `If-Modified-Since`
+/// when both are sent.
+pub fn is_not_modified(request: &HeaderMap, etag: &str, last_modified: Option<i64>) -> bool {
+    if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+        return match if_none_match.to_str() {
+            Ok(tags) => tags
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag),
+            Err(_) => false,
+        };
+    }
+
+    match (
+        last_modified,
+        request
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(parse_http_date),
+    ) {
+        (Some(last_modified), Some(since)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// `body` as json with its validators and `cache_control`, or an empty `304` if the client's
+/// copy is still fresh. `last_modified` is a unix time in milliseconds.
+pub fn conditional_json<T: Serialize>(
+    request: &HeaderMap,
+    body: &T,
+    last_modified: Option<i64>,
+    cache_control: &HeaderValue,
+) -> Response {
+    let json = match serde_json::to_vec(body) {
+        Ok(json) => json,
+        Err(e) => {
+            log::error!(target: "http cache", "{}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    conditional(
+        request,
+        json,
+        HeaderValue::from_static("application/json"),
+        last_modified,
+        cache_control,
+    )
+}
+
+/// `body` of `content_type` with its validators and `cache_control`, or an empty `304` if the
+/// client's copy is still fresh. `last_modified` is a unix time in milliseconds.
+pub fn conditional(
+    request: &HeaderMap,
+    body: Vec<u8>,
+    content_type: HeaderValue,
+    last_modified: Option<i64>,
+    cache_control: &HeaderValue,
+) -> Response {
+    let etag = etag(&body);
+    let last_modified = last_modified.map(|ms| ms / 1000);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, cache_control.clone());
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    if let Some(last_modified) = last_modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&http_date_from_unix(last_modified)).unwrap(),
+        );
+    }
+
+    if is_not_modified(request, &etag, last_modified) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    headers.insert(header::CONTENT_TYPE, content_type);
+
+    (StatusCode::OK, headers, body).into_response()
+}
+
+#[test]
+fn not_modified() {
+    let etag = etag(br#"{"count":0,"videos":[]}"#);
+    let last_modified = Some(1_647_779_400);
+
+    let request = |name: header::HeaderName, value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    };
+
+    assert!(!is_not_modified(&HeaderMap::new(), &etag, last_modified));
+    assert!(is_not_modified(
+        &request(header::IF_NONE_MATCH, &format!("\"x\", W/{}", etag)),
+        &etag,
+        last_modified
+    ));
+    assert!(!is_not_modified(
+        &request(header::IF_NONE_MATCH, "\"x\""),
+        &etag,
+        last_modified
+    ));
+    assert!(is_not_modified(
+        &request(header::IF_MODIFIED_SINCE, "Sun, 20 Mar 2022 12:30:00 GMT"),
+        &etag,
+        last_modified
+    ));
+    assert!(!is_not_modified(
+        &request(header::IF_MODIFIED_SINCE, "Sun, 20 Mar 2022 12:29:59 GMT"),
+        &etag,
+        last_modified
+    ));
+}
+
+#[test]
+fn conditional_feed() {
+    let cache_control = HeaderValue::from_static("public, max-age=300");
+    let feed = |if_modified_since: &str| {
+        let mut request = HeaderMap::new();
+        request.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_str(if_modified_since).unwrap(),
+        );
+        conditional(
+            &request,
+            b"<rss/>".to_vec(),
+            HeaderValue::from_static("application/rss+xml; charset=utf-8"),
+            Some(1_647_779_400_000),
+            &cache_control,
+        )
+    };
+
+    let res = feed("Sun, 20 Mar 2022 12:29:59 GMT");
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "application/rss+xml; charset=utf-8"
+    );
+    assert_eq!(
+        res.headers()[header::LAST_MODIFIED],
+        "Sun, 20 Mar 2022 12:30:00 GMT"
+    );
+
+    let res = feed("Sun, 20 Mar 2022 12:30:00 GMT");
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=300");
+}
@@ -3,49 +3,193 @@ extern crate dotenv_codegen;
 
 use axum::{
     extract::{Extension, Path, Query},
-    response::Json,
-    routing::get,
+    response::sse::{self, KeepAlive, Sse},
+    response::{Json, Response},
+    routing::{get, post},
     Router,
 };
-use std::collections::HashMap;
+use futures::stream::{self, Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 
 use dotenv::dotenv;
 
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 
-use http::Method;
+use http::{HeaderMap, Method, StatusCode};
 
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+mod admin;
+mod api_keys;
 mod brightcove;
+mod cache;
+mod cli;
 mod db;
+mod feeds;
+mod health;
+mod http_cache;
+mod metrics;
+mod oembed;
+mod playback_rights;
+mod racedays;
+mod site;
+mod sitemap;
+mod subscriptions;
+mod supervisor;
+mod webhooks;
+mod writer;
 
-use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+};
+use sqlx::{ConnectOptions, Connection};
+use std::net::SocketAddr;
+use std::str::FromStr;
 
 use std::time::Duration;
-use tokio::{sync::Mutex, task, time};
+use tokio::{
+    sync::{watch, Mutex, Notify},
+    time,
+};
+
+/// How often the outgoing webhooks queue is checked for due deliveries.
+const WEBHOOK_DELIVERY_DELAY_IN_S: u64 = 5;
+/// How long a connection waits for the db to be unlocked before failing with `SQLITE_BUSY`.
+const DEFAULT_DATABASE_BUSY_TIMEOUT_IN_MS: u64 = 5000;
+const DEFAULT_DATABASE_READ_CONNECTIONS: u32 = 10;
+/// How long the requests and the tasks have to finish on shutdown before they're aborted.
+const DEFAULT_SHUTDOWN_TIMEOUT_IN_S: u64 = 30;
+/// Most ids `?videos=` takes.
+const MAX_VIDEO_IDS: usize = 100;
+/// How long the runs of the syncs are kept in `sync_runs`.
+const SYNC_RUNS_RETENTION_IN_DAYS: i64 = 30;
+/// The tasks `serve --no-sync` starts paused.
+const SYNC_TASKS: [&str; 4] = [
+    "sync_videos",
+    "sync_views",
+    "sync_analytics",
+    "sync_playlists",
+];
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
 
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info,tower_http=debug")
     }
 
-    tracing_subscriber::fmt::init();
+    // the other commands print their result, the logs go apart
+    match command {
+        cli::Command::Serve { .. } => tracing_subscriber::fmt::init(),
+        _ => tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+
+    match command {
+        cli::Command::Serve { bind, sync } => serve(bind, sync).await,
+        cli::Command::Sync { target, once } => sync(target, once).await,
+        cli::Command::Migrate => {
+            open_db().await?;
+            log::info!("migrations done");
+            Ok(())
+        }
+        cli::Command::Backfill { from, to } => backfill(&from, &to).await,
+        cli::Command::Token => {
+            println!("{}", brightcove::get_access_token().await?);
+            Ok(())
+        }
+        cli::Command::ApiKeys(command) => api_keys_command(command).await,
+        cli::Command::Help => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+    }
+}
+
+/// `name` parsed as a `T`, `default` if it's unset or empty.
+fn env_var<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse::<T>()
+            .map_err(|e| anyhow::anyhow!("invalid {}: {}", name, e)),
+        _ => Ok(default),
+    }
+}
 
-    let ro_pool = SqlitePool::connect(dotenv!("DATABASE_URL")).await?;
-    let rw_pool = SqlitePool::connect(dotenv!("DATABASE_URL")).await?;
-    sqlx::migrate!().run(&rw_pool).await?;
+/// The db, migrated: the writer on its own connection and the read pool.
+struct Db {
+    writer: writer::Writer,
+    /// resolves once every writer handle is dropped and the batches queued are done
+    writer_closed: tokio::task::JoinHandle<()>,
+    pool: SqlitePool,
+    read_connections: u32,
+}
 
-    let brightcove_access_token = Arc::new(Mutex::new(brightcove::get_access_token().await));
-    let brightcove_access_token_for_thread = brightcove_access_token.clone();
-    let brightcove_access_token_for_video_views_thread = brightcove_access_token.clone();
+async fn open_db() -> anyhow::Result<Db> {
+    // WAL lets the handlers read while the writer writes
+    let db_options = SqliteConnectOptions::from_str(dotenv!("DATABASE_URL"))?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_millis(env_var(
+            "DATABASE_BUSY_TIMEOUT_IN_MS",
+            DEFAULT_DATABASE_BUSY_TIMEOUT_IN_MS,
+        )?));
 
-    let rw_pool = Arc::new(Mutex::new(rw_pool));
-    let video_sync_pool = rw_pool.clone();
-    let video_views_pool = rw_pool.clone();
+    let mut write_conn = db_options.connect().await?;
+    sqlx::migrate!().run(&mut write_conn).await?;
+    let (writer, writer_closed) = writer::Writer::spawn(write_conn);
+
+    let read_connections = env_var(
+        "DATABASE_READ_CONNECTIONS",
+        DEFAULT_DATABASE_READ_CONNECTIONS,
+    )?;
+    let pool = SqlitePoolOptions::new()
+        .max_connections(read_connections)
+        .connect_with(db_options)
+        .await?;
+
+    Ok(Db {
+        writer,
+        writer_closed,
+        pool,
+        read_connections,
+    })
+}
+
+/// Serves the API on `bind` and runs the background tasks, until SIGINT or SIGTERM. Without
+/// `sync` the sync tasks start paused.
+async fn serve(bind: SocketAddr, sync: bool) -> anyhow::Result<()> {
+    let Db {
+        writer,
+        writer_closed,
+        pool: ro_pool,
+        read_connections,
+    } = open_db().await?;
+
+    let shutdown_timeout = Duration::from_secs(env_var(
+        "SHUTDOWN_TIMEOUT_IN_S",
+        DEFAULT_SHUTDOWN_TIMEOUT_IN_S,
+    )?);
+    let pool_for_shutdown = ro_pool.clone();
+
+    let brightcove_access_token = Arc::new(Mutex::new(brightcove::get_access_token().await?));
 
     let thread_get_access_token_interval = dotenv!("THREAD_GET_ACCESS_TOKEN_DELAY_IN_S")
         .parse::<u64>()
@@ -59,194 +203,1584 @@ async fn main() -> anyhow::Result<()> {
         .parse::<u64>()
         .unwrap();
 
+    let thread_sync_views_full_interval = dotenv!("THREAD_SYNC_VIEWS_FULL_DELAY_IN_S")
+        .parse::<u64>()
+        .unwrap();
+
+    let thread_sync_analytics_interval = dotenv!("THREAD_SYNC_ANALYTICS_DELAY_IN_S")
+        .parse::<u64>()
+        .unwrap();
+
+    let thread_sync_playlists_interval = dotenv!("THREAD_SYNC_PLAYLISTS_DELAY_IN_S")
+        .parse::<u64>()
+        .unwrap();
+
+    // restarts the background tasks when they panic
+    let supervisor = supervisor::Supervisor::new();
+
+    // the admin routes (and the brightcove webhook, for the video sync) wake the tasks up
+    // without waiting for their interval
+    let triggers = admin::Triggers::new();
+
     // thread that gets access token
-    task::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(thread_get_access_token_interval));
+    let (token_for_thread, token_refresh) = (
+        brightcove_access_token.clone(),
+        triggers.token_refresh.clone(),
+    );
+    supervisor.spawn("access_token", move |task| {
+        let (brightcove_access_token, token_refresh) =
+            (token_for_thread.clone(), token_refresh.clone());
 
-        loop {
-            interval.tick().await;
-            log::info!(target: "get_access_token", "time expired, getting new token");
-            let new_brightcove_access_token = brightcove::get_access_token().await;
+        async move {
+            let mut interval =
+                time::interval(Duration::from_secs(thread_get_access_token_interval));
 
-            let mut brightcove_access_token = brightcove_access_token_for_thread.lock().await;
-            *brightcove_access_token = new_brightcove_access_token;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = token_refresh.notified() => {}
+                    _ = task.stopped() => break,
+                }
+                if !task.unpaused().await {
+                    break;
+                }
+                log::info!(target: "get_access_token", "time expired, getting new token");
+                match brightcove::get_access_token().await {
+                    Ok(new_brightcove_access_token) => {
+                        *brightcove_access_token.lock().await = new_brightcove_access_token;
+                        task.success();
+                    }
+                    // the old token is kept, it may still be valid until the next try
+                    Err(e) => {
+                        log::error!(target: "get_access_token", "{}", e);
+                        task.failure(e);
+                    }
+                }
+            }
         }
     });
 
+    // signals the video streams that the sync inserted videos
+    let (new_videos_tx, new_videos_rx) = watch::channel(());
+    let new_videos_tx = Arc::new(new_videos_tx);
+
+    let video_cache = Arc::new(cache::VideoCache::from_env()?);
+    video_cache.load_changed_at(&ro_pool).await?;
+
     // thread that syncs videos
-    task::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(thread_sync_video_interval));
-
-        loop {
-            log::info!(target: "sync videos", "checking new videos");
-
-            // TODO: cosa succede se si elimina un video da BC? come lo si risincronizza?
-
-            /*
-             * - get latest_bc_video_id from db
-             * - create empty array `let bc_videos: Vec<XmlVideoAsset> = vec![];`
-             * - [bc api] get playable videos, sort by created_at, paginate 25
-             * - iterate over playable videos until video_id == latest_bc_video_id,
-             *   adding videos to bc_videos
-             *
-             * - for each bc_videos create database row
-             *
-             */
-
-            // get latest_bc_video_id from db
-            let mut conn = video_sync_pool.lock().await.acquire().await.unwrap();
-            let latest_bc_video_id = db::get_latest_bc_video_id(&mut conn).await;
-
-            let latest_bc_video_id = match latest_bc_video_id {
-                Ok(id) => {
-                    log::info!(target: "sync videos","latest_bc_video_id: {}", &id.as_ref().unwrap());
-                    id
+    let (video_sync_pool, video_sync_writer, video_sync_cache, video_sync_triggers) = (
+        ro_pool.clone(),
+        writer.clone(),
+        video_cache.clone(),
+        triggers.clone(),
+    );
+    let video_sync_token = brightcove_access_token.clone();
+    supervisor.spawn("sync_videos", move |task| {
+        let (pool, writer, video_cache, triggers, new_videos_tx, brightcove_access_token) = (
+            video_sync_pool.clone(),
+            video_sync_writer.clone(),
+            video_sync_cache.clone(),
+            video_sync_triggers.clone(),
+            new_videos_tx.clone(),
+            video_sync_token.clone(),
+        );
+
+        async move {
+            let mut interval = time::interval(Duration::from_secs(thread_sync_video_interval));
+
+            while task.unpaused().await {
+                let started = std::time::Instant::now();
+
+                let result = match triggers.full_resync.swap(false, Ordering::SeqCst) {
+                    true => {
+                        log::info!(target: "sync videos", "resyncing the whole catalogue");
+                        let resync = async {
+                            let mut counts = db::SyncCounts::default();
+                            let result = resync_videos(
+                                &pool,
+                                &writer,
+                                &brightcove_access_token,
+                                &video_cache,
+                                &new_videos_tx,
+                                &triggers.views_sync,
+                                &mut counts,
+                            )
+                            .await;
+                            (counts, result)
+                        };
+                        record_sync_run(&writer, "resync_videos", resync).await
+                    }
+                    false => {
+                        log::info!(target: "sync videos", "checking new videos");
+                        let sync = async {
+                            let mut counts = db::SyncCounts::default();
+                            let result = sync_videos(
+                                &pool,
+                                &writer,
+                                &video_cache,
+                                &new_videos_tx,
+                                &mut counts,
+                            )
+                            .await;
+                            (counts, result)
+                        };
+                        record_sync_run(&writer, "sync_videos", sync).await
+                    }
+                };
+
+                match result {
+                    Ok(counts) => {
+                        log::info!(target: "sync videos", " {:?}", counts);
+                        metrics::increment("sync_videos_inserted_total", &[], counts.inserted);
+                        metrics::sync_pass("sync_videos", started, true);
+                        task.success();
+                    }
+                    Err(e) => {
+                        log::error!(target: "sync videos", " {}", e);
+                        metrics::sync_pass("sync_videos", started, false);
+                        task.failure(e);
+                    }
                 }
-                _ => {
-                    log::info!(target: "sync videos","info: no latest_bc_video_id");
-                    None
+
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = triggers.video_sync.notified() => {
+                        log::info!(target: "sync videos", "woken up by a notification");
+                    }
+                    _ = task.stopped() => break,
                 }
-            };
+            }
+        }
+    });
+
+    // thread that syncs views
+    //
+    // only the views since the last successful sync are asked to Analytics and added to the
+    // stored totals. every `thread_sync_views_full_interval` the totals are pulled again from
+    // `alltime`, this corrects the drift caused by views Analytics reports late.
+    let (video_views_pool, video_views_writer, video_views_token, video_views_cache) = (
+        ro_pool.clone(),
+        writer.clone(),
+        brightcove_access_token.clone(),
+        video_cache.clone(),
+    );
+    let views_sync_for_thread = triggers.views_sync.clone();
+    supervisor.spawn("sync_views", move |task| {
+        let (pool, writer, brightcove_access_token, video_cache, views_sync) = (
+            video_views_pool.clone(),
+            video_views_writer.clone(),
+            video_views_token.clone(),
+            video_views_cache.clone(),
+            views_sync_for_thread.clone(),
+        );
+
+        async move {
+            let mut interval = time::interval(Duration::from_secs(thread_sync_views_interval));
 
-            // create empty array `let bc_videos: Vec<XmlVideoAsset> = vec![];`
-            // [bc api] get playable videos, sort by created_at, paginate 25
-            let new_videos_result = brightcove::get_new_videos(latest_bc_video_id.clone()).await;
-
-            if let Ok(new_videos) = new_videos_result {
-                match new_videos {
-                    Some(new_videos) => {
-                        log::debug!(target: "sync videos"," saving {} new videos", &new_videos.len());
-
-                        match db::save_videos(&mut conn, &new_videos).await {
-                            Ok(_) => {
-                                log::info!(target: "sync videos"," saved {} new videos", &new_videos.len())
-                            }
-                            Err(e) => log::error!(target: "sync videos",
-                                " fail saved {} new videos: {}",
-                                &new_videos.len(),
-                                e.to_string()
-                            ),
+            while task.unpaused().await {
+                let started = std::time::Instant::now();
+                let sync = async {
+                    let mut counts = db::SyncCounts::default();
+                    let result = sync_views(
+                        &pool,
+                        &writer,
+                        &brightcove_access_token,
+                        &video_cache,
+                        thread_sync_views_full_interval,
+                        || task.stopping(),
+                        &mut counts,
+                    )
+                    .await;
+                    (counts, result)
+                };
+                match record_sync_run(&writer, "sync_views", sync).await {
+                    Ok(counts) => {
+                        log::info!(target: "sync views", "updated views for {} videos", counts.updated);
+                        metrics::increment("sync_views_updated_total", &[], counts.updated);
+                        metrics::sync_pass("sync_views", started, true);
+                        task.success();
+                    }
+                    Err(e) => {
+                        log::error!(target: "sync views", "{}", e);
+                        metrics::sync_pass("sync_views", started, false);
+                        task.failure(e);
+                    }
+                }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = views_sync.notified() => {
+                        log::info!(target: "sync views", "woken up by a notification");
+                    }
+                    _ = task.stopped() => break,
+                }
+            }
+        }
+    });
+
+    // thread that syncs the analytics breakdowns (date, device_type, country)
+    let (analytics_writer, analytics_token) = (writer.clone(), brightcove_access_token.clone());
+    supervisor.spawn("sync_analytics", move |task| {
+        let (writer, brightcove_access_token) = (analytics_writer.clone(), analytics_token.clone());
+
+        async move {
+            let mut interval = time::interval(Duration::from_secs(thread_sync_analytics_interval));
+
+            while task.unpaused().await {
+                let started = std::time::Instant::now();
+                let mut failed = false;
+                for dimension in brightcove::analytics::Dimension::ALL {
+                    if task.stopping() {
+                        break;
+                    }
+                    match sync_analytics(&writer, &brightcove_access_token, dimension).await {
+                        Ok(saved) => log::info!(
+                            target: "sync analytics",
+                            "saved {} {} rows",
+                            saved,
+                            dimension.as_str()
+                        ),
+                        Err(e) => {
+                            log::error!(target: "sync analytics", "{}: {}", dimension.as_str(), e);
+                            task.failure(format!("{}: {}", dimension.as_str(), e));
+                            failed = true;
                         }
                     }
-                    None => {
-                        log::info!(target: "sync videos"," no new videos");
+                }
+                metrics::sync_pass("sync_analytics", started, !failed);
+                if !failed {
+                    task.success();
+                }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = task.stopped() => break,
+                }
+            }
+        }
+    });
+
+    // thread that syncs playlists
+    let (playlists_writer, playlists_token) = (writer.clone(), brightcove_access_token.clone());
+    supervisor.spawn("sync_playlists", move |task| {
+        let (writer, brightcove_access_token) = (playlists_writer.clone(), playlists_token.clone());
+
+        async move {
+            let mut interval = time::interval(Duration::from_secs(thread_sync_playlists_interval));
+
+            while task.unpaused().await {
+                let started = std::time::Instant::now();
+                match sync_playlists(&writer, &brightcove_access_token).await {
+                    Ok(saved) => {
+                        log::info!(target: "sync playlists", "saved {} playlists", saved);
+                        metrics::sync_pass("sync_playlists", started, true);
+                        task.success();
+                    }
+                    Err(e) => {
+                        log::error!(target: "sync playlists", "{}", e);
+                        metrics::sync_pass("sync_playlists", started, false);
+                        task.failure(e);
                     }
                 }
-            } else {
-                log::info!(target: "sync videos"," no new videos");
-            };
-            interval.tick().await;
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = task.stopped() => break,
+                }
+            }
         }
     });
 
-    // thread that syncs views
-    task::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(thread_sync_views_interval));
-
-        loop {
-            let mut conn = video_views_pool.lock().await.acquire().await.unwrap();
-
-            let brightcove_access_token =
-                brightcove_access_token_for_video_views_thread.lock().await;
-
-            let video_views_response: anyhow::Result<brightcove::analytics::VideosResponse> =
-                brightcove::get_all_video_views(&brightcove_access_token).await;
-
-            match video_views_response {
-                Ok(response) => {
-                    if response.item_count > 0 {
-                        for item in response.items {
-                            match &item.video {
-                                Some(video) => {
-                                    match db::update_video_views(&mut conn, video, &item.video_view)
-                                        .await
-                                    {
-                                        Ok(_) => {
-                                            log::debug!(
-                                                target: "sync views",
-                                                "updated views for video: {:?}",
-                                                &item.video
-                                            )
-                                        }
-                                        _ => log::debug!(
-                                        target: "sync views",
-                                                                                    "error updating video: {:?}",
-                                                                                    &item.video
-                                                                                ),
-                                    };
-                                }
-                                None => {}
-                            }
+    if !sync {
+        log::info!("--no-sync, the sync tasks start paused");
+        for name in SYNC_TASKS {
+            supervisor.set_paused(name, true);
+        }
+    }
+
+    // thread that delivers the outgoing webhooks
+    let (webhooks_pool, webhooks_writer) = (ro_pool.clone(), writer.clone());
+    supervisor.spawn("deliver_webhooks", move |task| {
+        let (pool, writer) = (webhooks_pool.clone(), webhooks_writer.clone());
+
+        async move {
+            let mut interval = time::interval(Duration::from_secs(WEBHOOK_DELIVERY_DELAY_IN_S));
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = task.stopped() => break,
+                }
+                if !task.unpaused().await {
+                    break;
+                }
+                match subscriptions::deliver_due(&pool, &writer, unix_time_in_ms()).await {
+                    Ok(attempted) => {
+                        if attempted > 0 {
+                            log::info!(target: "webhooks", "attempted {} deliveries", attempted);
                         }
-                        // update record
+                        task.success();
+                    }
+                    Err(e) => {
+                        log::error!(target: "webhooks", "{}", e);
+                        task.failure(e);
                     }
                 }
-                Err(e) => log::error!(
-                   target: "sync views", "{}", e),
             }
-            interval.tick().await;
         }
     });
 
     let cors = CorsLayer::new()
-        .allow_methods(vec![Method::GET])
-        .allow_headers(vec![http::header::CONTENT_TYPE])
-        .allow_headers(vec![http::header::ACCEPT])
+        .allow_methods(vec![Method::GET, Method::POST])
+        .allow_headers(vec![
+            http::header::CONTENT_TYPE,
+            http::header::ACCEPT,
+            http::header::IF_NONE_MATCH,
+            http::header::IF_MODIFIED_SINCE,
+            http::header::AUTHORIZATION,
+            http::header::HeaderName::from_static("x-api-key"),
+        ])
+        .expose_headers(vec![
+            http::header::ETAG,
+            http::header::RETRY_AFTER,
+            http::header::HeaderName::from_static("ratelimit-limit"),
+            http::header::HeaderName::from_static("ratelimit-remaining"),
+            http::header::HeaderName::from_static("ratelimit-reset"),
+        ])
         .allow_origin(Any);
 
+    let playback_rights = playback_rights::PlaybackRights::from_env()?.map(Arc::new);
+    if playback_rights.is_none() {
+        log::info!("PLAYBACK_RIGHTS_PRIVATE_KEY_PATH not set, playback tokens disabled");
+    }
+
+    let api_keys = Arc::new(api_keys::ApiKeys::from_env(ro_pool.clone())?);
+
     let routes = Router::new()
         .route("/videos", get(videos_index))
+        .route("/videos/stream", get(videos_stream))
         .route("/videos/:video_id", get(video_show))
+        .route("/videos/:video_id/jsonld", get(video_jsonld))
+        .route(
+            "/videos/:video_id/playback-token",
+            post(playback_token_create),
+        )
+        .route("/racedays", get(racedays_index))
+        .route("/racedays/:date", get(raceday_show))
+        .route("/races/:date/:ippodromo/:numero", get(race_show))
+        .route("/playlists", get(playlists_index))
+        .route("/playlists/:playlist_id", get(playlist_show))
+        .route("/analytics", get(analytics_index))
+        .route("/analytics/:dimension", get(analytics_show))
+        .layer(axum::middleware::from_fn(api_keys::authenticate))
+        .layer(Extension(api_keys.clone()))
+        .layer(axum::middleware::from_fn(metrics::matched_route))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(ro_pool));
+        .layer(Extension(ro_pool.clone()))
+        .layer(Extension(http_cache::CacheControl::from_env()?))
+        .layer(Extension(playback_rights))
+        .layer(Extension(new_videos_rx))
+        .layer(Extension(video_cache.clone()))
+        .layer(Extension(brightcove_access_token.clone()));
 
-    let app = Router::new().nest("/api/v1", routes);
+    let ro_pool_for_webhooks = ro_pool.clone();
+    let webhooks_token = brightcove_access_token.clone();
+    let readyz_thresholds = health::Thresholds::from_env(
+        thread_get_access_token_interval,
+        thread_sync_video_interval,
+        thread_sync_views_interval,
+    )?;
+    let app = Router::new()
+        .merge(health::router(
+            ro_pool.clone(),
+            writer.clone(),
+            supervisor.clone(),
+            brightcove_access_token,
+            readyz_thresholds,
+        ))
+        .nest("/api/v1", routes)
+        .nest(
+            "/feeds",
+            feeds::router(ro_pool.clone(), api_keys.anonymous(), video_cache.clone())
+                .layer(axum::middleware::from_fn(metrics::matched_route)),
+        )
+        .merge(sitemap::router(ro_pool.clone(), api_keys.anonymous()))
+        .merge(oembed::router(ro_pool.clone(), api_keys.anonymous()))
+        .nest(
+            "/admin",
+            admin::router(
+                triggers.clone(),
+                supervisor.clone(),
+                ro_pool.clone(),
+                writer.clone(),
+                api_keys,
+                video_cache.clone(),
+            )
+            .layer(axum::middleware::from_fn(metrics::matched_route)),
+        )
+        .merge(metrics::router(
+            ro_pool,
+            read_connections,
+            supervisor.clone(),
+            video_cache.clone(),
+        ))
+        .nest(
+            "/webhooks",
+            webhooks::router(
+                triggers.video_sync,
+                webhooks_token,
+                ro_pool_for_webhooks,
+                writer,
+                video_cache,
+            )
+            .layer(axum::middleware::from_fn(metrics::matched_route)),
+        )
+        .layer(axum::middleware::from_fn(metrics::track_requests));
 
-    axum::Server::bind(&"0.0.0.0:4000".parse()?)
-        .serve(app.into_make_service())
+    let (stop_server, server_stopping) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::Server::bind(&bind)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async {
+                server_stopping.await.ok();
+            }),
+    );
+
+    tokio::select! {
+        result = &mut server => return Ok(result??),
+        _ = shutdown_signal() => {}
+    }
+
+    log::info!("shutting down, waiting up to {:?}", shutdown_timeout);
+
+    // the server stops accepting connections and drains the requests, while the tasks finish
+    // their pass. the video streams end with the video sync, which holds their sender
+    let _ = stop_server.send(());
+    let drain = async {
+        if time::timeout(shutdown_timeout, &mut server).await.is_err() {
+            log::warn!(
+                "requests still running after {:?}, closing them",
+                shutdown_timeout
+            );
+            server.abort();
+        }
+    };
+    tokio::join!(drain, supervisor.shutdown(shutdown_timeout));
+
+    // the writer handles went with the router and the tasks (bar a video refresh requested by
+    // a notification), the writer closes its connection once the batches queued are done
+    if time::timeout(shutdown_timeout, writer_closed)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "db writer still busy after {:?}, leaving it",
+            shutdown_timeout
+        );
+    }
+    pool_for_shutdown.close().await;
+    log::info!("stopped");
+
+    Ok(())
+}
+
+/// Resolves on SIGINT (ctrl-c) or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("error listening for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("error listening for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// `sync videos|views`: runs the sync without the server, once or on its interval until
+/// SIGINT or SIGTERM. The runs are recorded like the ones of `serve`.
+async fn sync(target: cli::SyncTarget, once: bool) -> anyhow::Result<()> {
+    let db = open_db().await?;
+    // nothing reads them here, the sync functions want them
+    let video_cache = cache::VideoCache::from_env()?;
+    let (new_videos_tx, _) = watch::channel(());
+    let brightcove_access_token = Mutex::new(String::new());
+
+    let (task, interval) = match target {
+        cli::SyncTarget::Videos => ("sync_videos", dotenv!("THREAD_SYNC_VIDEO_DELAY_IN_S")),
+        cli::SyncTarget::Views => ("sync_views", dotenv!("THREAD_SYNC_VIEWS_DELAY_IN_S")),
+    };
+    let mut interval = time::interval(Duration::from_secs(interval.parse::<u64>()?));
+    let views_full_interval = dotenv!("THREAD_SYNC_VIEWS_FULL_DELAY_IN_S").parse::<u64>()?;
+
+    // a signal lets the pass running finish, the views sync stops between pages
+    let stopping = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let mut stopped = match once {
+        true => None,
+        false => {
+            let stopping = stopping.clone();
+            Some(tokio::spawn(async move {
+                shutdown_signal().await;
+                log::info!("stopping after this pass");
+                stopping.store(true, Ordering::SeqCst);
+            }))
+        }
+    };
+
+    // the first tick is now
+    interval.tick().await;
+    loop {
+        let pass = async {
+            let mut counts = db::SyncCounts::default();
+            let result = match target {
+                cli::SyncTarget::Videos => {
+                    sync_videos(
+                        &db.pool,
+                        &db.writer,
+                        &video_cache,
+                        &new_videos_tx,
+                        &mut counts,
+                    )
+                    .await
+                }
+                cli::SyncTarget::Views => match brightcove::get_access_token().await {
+                    Ok(token) => {
+                        *brightcove_access_token.lock().await = token;
+                        sync_views(
+                            &db.pool,
+                            &db.writer,
+                            &brightcove_access_token,
+                            &video_cache,
+                            views_full_interval,
+                            || stopping.load(Ordering::SeqCst),
+                            &mut counts,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            (counts, result)
+        };
+
+        match record_sync_run(&db.writer, task, pass).await {
+            Ok(counts) => log::info!(target: "sync", "{} {:?}", task, counts),
+            Err(e) if once => return Err(e),
+            Err(e) => log::error!(target: "sync", "{}: {}", task, e),
+        }
+
+        let stopped = match stopped.as_mut() {
+            Some(stopped) if !stopping.load(Ordering::SeqCst) => stopped,
+            _ => break,
+        };
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stopped => break,
+        }
+    }
+
+    close_db(db).await;
+
+    Ok(())
+}
+
+/// `backfill`: pulls again the videos created between `from` and `to` from the Playback API,
+/// inserting the ones missing from the db and updating the others. Recorded as
+/// `backfill_videos` in `sync_runs`.
+async fn backfill(from: &str, to: &str) -> anyhow::Result<()> {
+    let db = open_db().await?;
+
+    let pass = async {
+        let mut counts = db::SyncCounts::default();
+        let result = async {
+            let videos = brightcove::get_videos_created_between(from, to).await?;
+            log::info!(target: "backfill", "{} videos created from {} to {}", videos.len(), from, to);
+
+            let known: HashSet<String> =
+                db::get_all_video_ids(&db.pool).await?.into_iter().collect();
+            counts.fetched = videos.len() as u64;
+            upsert_videos(&db.writer, &known, videos, &mut counts).await;
+
+            Ok(())
+        }
+        .await;
+        (counts, result)
+    };
+    let result = record_sync_run(&db.writer, "backfill_videos", pass).await;
+
+    close_db(db).await;
+
+    log::info!(target: "backfill", "{:?}", result?);
+
+    Ok(())
+}
+
+/// `api-keys list|create|revoke`, the keys are printed as JSON like `/admin/api-keys` does.
+async fn api_keys_command(command: cli::ApiKeysCommand) -> anyhow::Result<()> {
+    let db = open_db().await?;
+
+    let result = match command {
+        cli::ApiKeysCommand::List => db::get_api_keys(&db.pool)
+            .await
+            .and_then(|keys| Ok(serde_json::to_string_pretty(&keys)?)),
+        cli::ApiKeysCommand::Create {
+            name,
+            scopes,
+            rate_limit,
+        } => {
+            let rate_limit = match rate_limit {
+                Some(rate_limit) => rate_limit,
+                None => api_keys::ApiKeys::from_env(db.pool.clone())?.default_rate_limit,
+            };
+            api_keys::create(&db.writer, name, &scopes, rate_limit)
+                .await
+                .and_then(|(api_key, key)| {
+                    let mut api_key = serde_json::to_value(api_key)?;
+                    api_key["key"] = key.into();
+                    Ok(serde_json::to_string_pretty(&api_key)?)
+                })
+        }
+        cli::ApiKeysCommand::Revoke(id) => {
+            let now = unix_time_in_ms();
+            match db
+                .writer
+                .run(move |conn| Box::pin(db::revoke_api_key(conn, id, now)))
+                .await
+            {
+                // a server forgets the keys it looked up within a minute
+                Ok(true) => Ok(format!("api key {} revoked", id)),
+                Ok(false) => Err(anyhow::anyhow!("no api key {}, or already revoked", id)),
+                Err(e) => Err(e),
+            }
+        }
+    };
+
+    close_db(db).await;
+    println!("{}", result?);
+
+    Ok(())
+}
+
+/// Waits for the writer to finish the batches queued, then closes the read pool.
+async fn close_db(db: Db) {
+    let Db {
+        writer,
+        writer_closed,
+        pool,
+        ..
+    } = db;
+
+    drop(writer);
+    if let Err(e) = writer_closed.await {
+        log::error!("db writer: {}", e);
+    }
+    pool.close().await;
+}
+
+/// Pages through the views Analytics has since the last sync (or since `alltime`, see the
+/// views thread) saving each page as it arrives. Returns the rows fetched and the videos
+/// updated in `counts`.
+///
+/// Between pages it gives up if `stopping()`, failing like an Analytics error would.
+async fn sync_views(
+    pool: &SqlitePool,
+    writer: &writer::Writer,
+    brightcove_access_token: &Mutex<String>,
+    video_cache: &cache::VideoCache,
+    full_interval: u64,
+    stopping: impl Fn() -> bool,
+    counts: &mut db::SyncCounts,
+) -> anyhow::Result<()> {
+    let (last_sync, last_full_sync) = {
+        let mut conn = pool.acquire().await?;
+        (
+            db::get_synced_at(&mut conn, "views").await?,
+            db::get_synced_at(&mut conn, "views_full").await?,
+        )
+    };
+
+    let now = unix_time_in_ms();
+
+    let (sync, from) = match (last_sync, last_full_sync) {
+        (Some(last_sync), Some(last_full_sync))
+            if now - last_full_sync < full_interval as i64 * 1000 =>
+        {
+            (db::ViewsSync::Delta, last_sync.to_string())
+        }
+        _ => (db::ViewsSync::Full, "alltime".to_string()),
+    };
+
+    log::info!(target: "sync views", "{:?} sync from {} to {}", sync, from, now);
+
+    let to = now.to_string();
+    let pages: anyhow::Result<()> = async {
+        let pages = brightcove::analytics_pages(|offset| {
+            let (from, to) = (&from, &to);
+            async move {
+                let brightcove_access_token = brightcove_access_token.lock().await.clone();
+                brightcove::get_video_views_page(&brightcove_access_token, from, to, offset).await
+            }
+        });
+        futures::pin_mut!(pages);
+        let mut offset = 0;
+
+        while let Some(page) = pages.try_next().await? {
+            let item_count = page.item_count;
+            let video_ids: Vec<String> =
+                page.items.iter().filter_map(|v| v.video.clone()).collect();
+            counts.fetched += page.items.len() as u64;
+            let items = page.items;
+            counts.updated += writer
+                .run(move |conn| {
+                    Box::pin(async move { db::save_video_views(conn, &items, sync).await })
+                })
+                .await?;
+            video_cache.invalidate_videos(video_ids.iter().map(|id| id.as_str()));
+            offset += brightcove::ANALYTICS_ITEMS_PER_PAGE;
+
+            // shutting down, a safe point: the pages saved are undone by the next run
+            if offset < item_count && stopping() {
+                anyhow::bail!("stopped after {} of {} rows", offset, item_count);
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // the pages already added would be added again by the next delta, let the next run be a
+    // full one which replaces them
+    if pages.is_err() && sync == db::ViewsSync::Delta {
+        writer
+            .run(|conn| Box::pin(db::clear_synced_at(conn, "views_full")))
+            .await?;
+    }
+    pages?;
+    let updated = counts.updated;
+
+    writer
+        .run(move |conn| {
+            Box::pin(async move {
+                db::set_views_synced_at(conn, sync, now).await?;
+
+                subscriptions::enqueue(
+                    conn,
+                    subscriptions::Event::ViewsUpdated,
+                    &subscriptions::ViewsUpdated {
+                        sync: format!("{:?}", sync).to_lowercase(),
+                        videos_updated: updated,
+                        synced_at: now,
+                    },
+                    now,
+                )
+                .await?;
+
+                Ok(())
+            })
+        })
         .await?;
 
     Ok(())
 }
 
+/// Runs a pass of a sync, recording it in `sync_runs` as `task` with what it did, even if it
+/// failed. The runs older than `SYNC_RUNS_RETENTION_IN_DAYS` are dropped.
+async fn record_sync_run(
+    writer: &writer::Writer,
+    task: &'static str,
+    pass: impl std::future::Future<Output = (db::SyncCounts, anyhow::Result<()>)>,
+) -> anyhow::Result<db::SyncCounts> {
+    let started_at = unix_time_in_ms();
+    let run_id = writer
+        .run(move |conn| Box::pin(db::start_sync_run(conn, task, started_at)))
+        .await?;
+
+    let (counts, result) = pass.await;
+
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let run_counts = counts.clone();
+    let finished = writer
+        .run(move |conn| {
+            Box::pin(async move {
+                let now = unix_time_in_ms();
+                db::finish_sync_run(conn, run_id, &run_counts, error.as_deref(), now).await?;
+                db::delete_sync_runs_before(conn, now - SYNC_RUNS_RETENTION_IN_DAYS * 86_400_000)
+                    .await?;
+
+                Ok(())
+            })
+        })
+        .await;
+    if let Err(e) = finished {
+        log::error!(target: "sync runs", "fail recording run {}: {}", run_id, e);
+    }
+
+    result.map(|()| counts)
+}
+
+/// Inserts the videos published after the newest one in the db, counting them in `counts`.
+async fn sync_videos(
+    pool: &SqlitePool,
+    writer: &writer::Writer,
+    video_cache: &cache::VideoCache,
+    new_videos_tx: &watch::Sender<()>,
+    counts: &mut db::SyncCounts,
+) -> anyhow::Result<()> {
+    /*
+     * - get latest_bc_video_id from db
+     * - create empty array `let bc_videos: Vec<XmlVideoAsset> = vec![];`
+     * - [bc api] get playable videos, sort by created_at, paginate 25
+     * - iterate over playable videos until video_id == latest_bc_video_id,
+     *   adding videos to bc_videos
+     *
+     * - for each bc_videos create database row
+     *
+     */
+
+    // get latest_bc_video_id from db
+    let latest_bc_video_id = {
+        let mut conn = pool.acquire().await?;
+        db::get_latest_bc_video_id(&mut conn).await
+    };
+
+    let latest_bc_video_id = match latest_bc_video_id {
+        Ok(id) => {
+            log::info!(target: "sync videos","latest_bc_video_id: {}", &id.as_ref().unwrap());
+            id
+        }
+        _ => {
+            log::info!(target: "sync videos","info: no latest_bc_video_id");
+            None
+        }
+    };
+
+    // create empty array `let bc_videos: Vec<XmlVideoAsset> = vec![];`
+    // [bc api] get playable videos, sort by created_at, paginate 25
+    let new_videos = match brightcove::get_new_videos(latest_bc_video_id).await {
+        Ok(Some(new_videos)) => new_videos,
+        Ok(None) => {
+            log::info!(target: "sync videos"," no new videos");
+            return Ok(());
+        }
+        Err(e) => anyhow::bail!("fail getting new videos: {}", e),
+    };
+
+    log::debug!(target: "sync videos"," saving {} new videos", &new_videos.len());
+    let new_videos_count = new_videos.len() as u64;
+    counts.fetched = new_videos_count;
+
+    if let Err(e) = writer
+        .run(move |conn| Box::pin(save_new_videos(conn, new_videos)))
+        .await
+    {
+        counts.failed = new_videos_count;
+        anyhow::bail!("fail saved {} new videos: {}", new_videos_count, e);
+    }
+
+    log::info!(target: "sync videos"," saved {} new videos", new_videos_count);
+    video_cache.invalidate_lists();
+    let _ = new_videos_tx.send(());
+
+    counts.inserted = new_videos_count;
+
+    Ok(())
+}
+
+/// Goes through the whole catalogue: inserts the videos missing from the db, updates the
+/// others and makes unavailable the ones Brightcove doesn't play anymore, counting them in
+/// `counts`. Only the inserts
+/// and the videos made unavailable queue webhooks. The views of every video are pulled again
+/// by a full views sync, woken up through `views_sync`.
+///
+/// The Playback API leaves out the videos deactivated, out of their schedule or geo
+/// restricted for the server, so a video missing from it is only made unavailable if the CMS
+/// API confirms it's deleted, inactive or out of its schedule.
+async fn resync_videos(
+    pool: &SqlitePool,
+    writer: &writer::Writer,
+    brightcove_access_token: &Mutex<String>,
+    video_cache: &cache::VideoCache,
+    new_videos_tx: &watch::Sender<()>,
+    views_sync: &Notify,
+    counts: &mut db::SyncCounts,
+) -> anyhow::Result<()> {
+    let videos = brightcove::get_new_videos(None).await?.unwrap_or_default();
+    // more likely a Brightcove hiccup than every video gone
+    if videos.is_empty() {
+        anyhow::bail!("no videos from Brightcove, nothing changed");
+    }
+
+    let brightcove_access_token = brightcove_access_token.lock().await.clone();
+    let catalogue: HashMap<String, brightcove::CmsVideo> =
+        brightcove::get_cms_videos(&brightcove_access_token)
+            .await?
+            .into_iter()
+            .map(|video| (video.id.clone(), video))
+            .collect();
+
+    let known: HashSet<String> = db::get_all_video_ids(pool).await?.into_iter().collect();
+    let playable: HashSet<String> = videos.iter().map(|v| v.bc_video_id.clone()).collect();
+
+    counts.fetched = videos.len() as u64;
+    let mut changed_ids = upsert_videos(writer, &known, videos, counts).await;
+
+    let now = unix_time_in_ms();
+    for id in known.difference(&playable).cloned() {
+        let event = match unavailable_event(catalogue.get(&id), now) {
+            Some(event) => event,
+            // playable, just not from where the server is
+            None => continue,
+        };
+
+        match make_unavailable(writer, id.clone(), event, now).await {
+            Ok(true) => counts.deleted += 1,
+            Ok(false) => {}
+            Err(e) => {
+                log::error!(target: "sync videos", "fail making {} unavailable: {}", id, e);
+                counts.failed += 1;
+            }
+        }
+        changed_ids.push(id);
+    }
+
+    video_cache.invalidate_videos(changed_ids.iter().map(|id| id.as_str()));
+    video_cache.invalidate_lists();
+    if counts.inserted > 0 {
+        let _ = new_videos_tx.send(());
+    }
+
+    writer
+        .run(|conn| Box::pin(db::clear_synced_at(conn, "views_full")))
+        .await?;
+    views_sync.notify_one();
+
+    Ok(())
+}
+
+/// Why a video missing from the Playback API isn't played anymore, from the CMS API
+/// `video`: `None` if it's still playable, just not from where the server is.
+fn unavailable_event(
+    video: Option<&brightcove::CmsVideo>,
+    now: i64,
+) -> Option<subscriptions::Event> {
+    match video {
+        None => Some(subscriptions::Event::VideoDeleted),
+        Some(video) if !video.is_playable(now / 1000) => {
+            Some(subscriptions::Event::VideoUnavailable)
+        }
+        Some(_) => None,
+    }
+}
+
+/// Makes the video unavailable and queues its `event` webhooks. Returns false if it isn't in
+/// the db or was already unavailable.
+async fn make_unavailable(
+    writer: &writer::Writer,
+    bc_video_id: String,
+    event: subscriptions::Event,
+    now: i64,
+) -> anyhow::Result<bool> {
+    writer
+        .run(move |conn| {
+            Box::pin(async move {
+                if !db::set_video_unavailable(conn, &bc_video_id, now).await? {
+                    return Ok(false);
+                }
+                subscriptions::enqueue(conn, event, &serde_json::json!({ "id": bc_video_id }), now)
+                    .await?;
+
+                Ok(true)
+            })
+        })
+        .await
+}
+
+/// Updates the `videos` in `known` and inserts the others, counting them in `counts`. Returns
+/// the ids changed.
+async fn upsert_videos(
+    writer: &writer::Writer,
+    known: &HashSet<String>,
+    videos: Vec<db::VideoRow>,
+    counts: &mut db::SyncCounts,
+) -> Vec<String> {
+    let mut changed_ids: Vec<String> = Vec::new();
+
+    let (existing, new_videos): (Vec<_>, Vec<_>) = videos
+        .into_iter()
+        .partition(|video| known.contains(&video.bc_video_id));
+
+    for video in existing {
+        let id = video.bc_video_id.clone();
+        match writer
+            .run(move |conn| Box::pin(async move { db::update_video(conn, &video).await }))
+            .await
+        {
+            Ok(true) => counts.updated += 1,
+            // deleted since
+            Ok(false) => {}
+            Err(e) => {
+                log::error!(target: "sync videos", "fail updating {}: {}", id, e);
+                counts.failed += 1;
+            }
+        }
+        changed_ids.push(id);
+    }
+
+    if !new_videos.is_empty() {
+        let new_videos_count = new_videos.len() as u64;
+        match writer
+            .run(move |conn| Box::pin(save_new_videos(conn, new_videos)))
+            .await
+        {
+            Ok(()) => counts.inserted += new_videos_count,
+            Err(e) => {
+                log::error!(target: "sync videos", "fail saved {} new videos: {}", new_videos_count, e);
+                counts.failed += new_videos_count;
+            }
+        }
+    }
+
+    changed_ids
+}
+
+/// Saves the videos found by the video sync and queues their `video.created` webhooks, in
+/// one transaction.
+async fn save_new_videos(
+    conn: &mut sqlx::SqliteConnection,
+    new_videos: Vec<db::VideoRow>,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    db::save_videos(&mut tx, &new_videos).await?;
+
+    let now = unix_time_in_ms();
+    for video in &new_videos {
+        let video: brightcove::Video = video.into();
+        // without its webhooks the video isn't saved either, the next sync tries both again
+        subscriptions::enqueue(&mut tx, subscriptions::Event::VideoCreated, &video, now).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Pages through the `dimension` breakdown saving each page as it arrives. Returns the number
+/// of rows saved.
+async fn sync_analytics(
+    writer: &writer::Writer,
+    brightcove_access_token: &Mutex<String>,
+    dimension: brightcove::analytics::Dimension,
+) -> anyhow::Result<usize> {
+    let pages = brightcove::analytics_pages(|offset| async move {
+        let brightcove_access_token = brightcove_access_token.lock().await.clone();
+        brightcove::get_video_views_breakdown_page(&brightcove_access_token, dimension, offset)
+            .await
+    });
+    futures::pin_mut!(pages);
+    let mut saved = 0;
+
+    while let Some(page) = pages.try_next().await? {
+        let items = page.items;
+        saved += items.len();
+        writer
+            .run(move |conn| {
+                Box::pin(async move { db::save_video_analytics(conn, dimension, &items).await })
+            })
+            .await?;
+    }
+
+    Ok(saved)
+}
+
+/// Saves every playlist of the account with its videos, and drops the ones removed from
+/// Brightcove. Returns the number of playlists saved.
+async fn sync_playlists(
+    writer: &writer::Writer,
+    brightcove_access_token: &Mutex<String>,
+) -> anyhow::Result<usize> {
+    let brightcove_access_token = brightcove_access_token.lock().await.clone();
+    let cms_playlists = brightcove::get_playlists(&brightcove_access_token).await?;
+
+    let mut saved = 0;
+    for cms_playlist in &cms_playlists {
+        match brightcove::get_playlist(&cms_playlist.id).await {
+            Ok(playlist) => {
+                writer
+                    .run(move |conn| {
+                        Box::pin(async move { db::save_playlist(conn, &playlist).await })
+                    })
+                    .await?;
+                saved += 1;
+            }
+            // e.g. playlists the policy key can't see, skip them and keep the rest in sync
+            Err(e) => log::error!(target: "sync playlists", "{}: {}", cms_playlist.id, e),
+        }
+    }
+
+    let bc_playlist_ids: Vec<String> = cms_playlists.into_iter().map(|p| p.id).collect();
+    let deleted = writer
+        .run(move |conn| {
+            Box::pin(async move { db::delete_playlists_except(conn, &bc_playlist_ids).await })
+        })
+        .await?;
+    if deleted > 0 {
+        log::info!(target: "sync playlists", "deleted {} playlists", deleted);
+    }
+
+    Ok(saved)
+}
+
+/// `?ippodromo=FIRENZE&tipologia=TROTTO&date=2022-03-20` filter the videos, see
+/// `db::VideoFilter`.
 async fn videos_index(
     pool: Extension<SqlitePool>,
-    params: Query<HashMap<String, u32>>,
-    _search_params: Option<Query<HashMap<String, String>>>,
-) -> Json<brightcove::PlayerResponse> {
-    let limit = match params.get("limit") {
+    access: Extension<api_keys::Access>,
+    video_cache: Extension<Arc<cache::VideoCache>>,
+    cache_control: Extension<http_cache::CacheControl>,
+    params: Query<HashMap<String, String>>,
+    filter: Query<db::VideoFilter>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    if !filter.is_valid() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let u32_param = |name: &str| match params.get(name) {
+        Some(value) => value
+            .parse::<u32>()
+            .map(Some)
+            .map_err(|_| StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    };
+
+    let limit = match u32_param("limit")? {
         Some(l) => {
-            if l > &20 {
+            if l > 20 {
                 20_u32
             } else {
-                *l
+                l
             }
         }
         None => 20,
     };
-    let offset = match params.get("offset") {
-        Some(offset) => *offset,
-        None => 0,
+    let offset = u32_param("offset")?.unwrap_or(0);
+
+    let (videos, changed_at) = video_cache
+        .get_videos(&pool, limit, offset, &filter)
+        .await
+        .map_err(|e| {
+            log::error!(target: "videos", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let videos = match access.can(api_keys::Scope::VideoViews) {
+        true => videos,
+        false => {
+            let mut videos = videos.as_ref().clone();
+            access.redact(&mut videos.videos);
+            Arc::new(videos)
+        }
     };
 
-    // TODO:
-    // - in mem sqlite
-    // - multithreaded sqlite
+    Ok(http_cache::conditional_json(
+        &headers,
+        videos.as_ref(),
+        changed_at,
+        &cache_control.videos,
+    ))
+}
+
+/// Server-Sent Events of the videos the sync inserts, filtered like `videos_index`. Each
+/// event id is the video row id, so a client reconnecting with `Last-Event-ID` gets what it
+/// missed from the db. Without it the stream starts from the next video inserted.
+async fn videos_stream(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    new_videos: Extension<watch::Receiver<()>>,
+    filter: Query<db::VideoFilter>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    if !filter.is_valid() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let last_event_id = match headers.get("last-event-id") {
+        Some(id) => id
+            .to_str()
+            .ok()
+            .and_then(|id| id.parse::<i64>().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => db::get_max_video_id(&pool).await.map_err(|e| {
+            log::error!(target: "videos stream", "{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+    };
 
-    //let videos = db::get_videos(&pool, &limit, &offset, &date, &cavalli).await;
-    let videos = db::get_videos(&pool, &limit, &offset).await;
+    let state = (
+        (pool.0, access.0),
+        new_videos.0,
+        filter.0,
+        last_event_id,
+        VecDeque::<db::VideoWithId>::new(),
+    );
+
+    let stream = stream::unfold(
+        state,
+        |((pool, access), mut new_videos, filter, mut last_id, mut pending)| async move {
+            loop {
+                if let Some(mut video) = pending.pop_front() {
+                    last_id = video.id;
+                    access.redact([&mut video.video]);
+                    let event = sse::Event::default()
+                        .id(video.id.to_string())
+                        .event("video")
+                        .json_data(&video.video)
+                        .unwrap_or_default();
+
+                    let state = ((pool, access), new_videos, filter, last_id, pending);
+                    return Some((Ok(event), state));
+                }
+
+                match db::get_videos_after(&pool, last_id, &filter, 100).await {
+                    Ok(videos) if !videos.is_empty() => pending.extend(videos),
+                    Ok(_) => {
+                        // the sender is gone when the server shuts down
+                        new_videos.changed().await.ok()?;
+                    }
+                    Err(e) => {
+                        log::error!(target: "videos stream", "{}", e);
+                        return None;
+                    }
+                }
+            }
+        },
+    );
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
 
-    Json(videos)
+#[derive(Debug, Deserialize)]
+struct VideoShowParams {
+    delivery: Option<brightcove::Delivery>,
 }
 
+/// `?delivery=hls|dash|mp4` keeps only the sources of that format.
 async fn video_show(
     pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    video_cache: Extension<Arc<cache::VideoCache>>,
+    cache_control: Extension<http_cache::CacheControl>,
     video_id: Path<String>,
-) -> Json<brightcove::Video> {
-    let video = db::get_video(&pool, &video_id).await;
+    params: Query<VideoShowParams>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (mut video, changed_at) = match video_cache.get_video(&pool, &video_id).await {
+        Ok(Some((video, changed_at))) => (video.as_ref().clone(), changed_at),
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!(target: "videos", "{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if let Some(delivery) = params.delivery {
+        video.sources.retain(|s| delivery.matches(s));
+    }
+    access.redact([&mut video]);
+
+    Ok(http_cache::conditional_json(
+        &headers,
+        &video,
+        changed_at,
+        &cache_control.video,
+    ))
+}
+
+/// schema.org `VideoObject` of the video, to embed as JSON-LD in its page.
+async fn video_jsonld(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    video_id: Path<String>,
+) -> Result<Json<site::VideoObject>, StatusCode> {
+    let mut video = find_video(&pool, &video_id).await?;
+    access.redact([&mut video]);
+
+    Ok(Json((&video).into()))
+}
+
+/// `?from=YYYY-MM-DD&to=YYYY-MM-DD` restricts the calendar, both are optional.
+async fn racedays_index(
+    pool: Extension<SqlitePool>,
+    video_cache: Extension<Arc<cache::VideoCache>>,
+    cache_control: Extension<http_cache::CacheControl>,
+    params: Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let date_param = |name: &str| match params.get(name) {
+        Some(date) => racedays::to_data(date)
+            .map(Some)
+            .ok_or(StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    };
+    let from = date_param("from")?;
+    let to = date_param("to")?;
+
+    // read before the racedays: a change during the read labels them older, never newer
+    let changed_at = video_cache.changed_at();
+    match db::get_racedays(&pool, from.as_deref(), to.as_deref()).await {
+        Ok(racedays) => Ok(http_cache::conditional_json(
+            &headers,
+            &racedays::RacedaysResponse {
+                count: racedays.len() as u32,
+                racedays,
+            },
+            changed_at,
+            &cache_control.racedays,
+        )),
+        Err(e) => {
+            log::error!(target: "racedays", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn raceday_show(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    video_cache: Extension<Arc<cache::VideoCache>>,
+    cache_control: Extension<http_cache::CacheControl>,
+    date: Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let data = racedays::to_data(&date).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let changed_at = video_cache.changed_at();
+    match db::get_videos_by_data(&pool, &data).await {
+        Ok(videos) if videos.is_empty() => Err(StatusCode::NOT_FOUND),
+        Ok(mut videos) => {
+            access.redact(&mut videos);
+
+            Ok(http_cache::conditional_json(
+                &headers,
+                &racedays::RacedayResponse {
+                    date: date.0,
+                    racecourses: racedays::group(videos),
+                },
+                changed_at,
+                &cache_control.racedays,
+            ))
+        }
+        Err(e) => {
+            log::error!(target: "racedays", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn race_show(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    Path((date, ippodromo, numero)): Path<(String, String, String)>,
+) -> Result<Json<racedays::RaceResponse>, StatusCode> {
+    let data = racedays::to_data(&date).ok_or(StatusCode::BAD_REQUEST)?;
+
+    match db::get_race(&pool, &data, &ippodromo, &numero).await {
+        Ok(Some(mut race)) => {
+            access.redact(&mut race.videos);
+            Ok(Json(race))
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!(target: "races", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn playlists_index(
+    pool: Extension<SqlitePool>,
+) -> Result<Json<brightcove::PlaylistsResponse>, StatusCode> {
+    match db::get_playlists(&pool).await {
+        Ok(playlists) => Ok(Json(playlists)),
+        Err(e) => {
+            log::error!(target: "playlists", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn playlist_show(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    playlist_id: Path<String>,
+) -> Result<Json<brightcove::PlaylistResponse>, StatusCode> {
+    match db::get_playlist(&pool, &playlist_id).await {
+        Ok(Some(mut playlist)) => {
+            access.redact(&mut playlist.videos);
+            Ok(Json(playlist))
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!(target: "playlists", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn find_video(pool: &SqlitePool, video_id: &str) -> Result<brightcove::Video, StatusCode> {
+    match db::get_video(pool, video_id).await {
+        Ok(Some(video)) => Ok(video),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!(target: "videos", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
 
-    Json(video)
+#[derive(Debug, Serialize)]
+struct PlaybackTokenResponse {
+    token: String,
+    expires_at: u64,
+}
+
+/// Signs a Playback Rights token for an API key with the `playback_token` scope, `401`
+/// without a key. The stream and device limits apply to the key.
+async fn playback_token_create(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    playback_rights: Extension<Option<Arc<playback_rights::PlaybackRights>>>,
+    video_id: Path<String>,
+) -> Result<Json<PlaybackTokenResponse>, StatusCode> {
+    let key_id = access.key_id.ok_or(StatusCode::UNAUTHORIZED)?;
+    access.require(api_keys::Scope::PlaybackToken)?;
+
+    let playback_rights = playback_rights
+        .as_ref()
+        .ok_or(StatusCode::NOT_IMPLEMENTED)?;
+
+    let video = find_video(&pool, &video_id).await?;
+
+    let uid = format!("api-key-{}", key_id);
+    let claims = playback_rights.claims(&video.id, Some(uid), unix_time_in_ms() as u64 / 1000);
+
+    match playback_rights.sign(&claims) {
+        Ok(token) => Ok(Json(PlaybackTokenResponse {
+            token,
+            expires_at: claims.exp,
+        })),
+        Err(e) => {
+            log::error!(target: "playback rights", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Views of `?videos=id1,id2` from the db. With `live=true` (and the `analytics_live` scope)
+/// the ids the db doesn't know yet are asked to Analytics. Ids unknown to both are left out.
+async fn analytics_index(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    brightcove_access_token: Extension<Arc<Mutex<String>>>,
+    params: Query<HashMap<String, String>>,
+) -> Result<Json<brightcove::analytics::VideosResponse>, StatusCode> {
+    access.require(api_keys::Scope::VideoViews)?;
+
+    let video_ids = video_ids_param(&params)?;
+    let live = params.get("live").map(|l| l == "true").unwrap_or(false);
+    if live {
+        access.require(api_keys::Scope::AnalyticsLive)?;
+    }
+
+    let mut items = match db::get_video_views(&pool, &video_ids).await {
+        Ok(items) => items,
+        Err(e) => {
+            log::error!(target: "analytics", "{}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let missing_ids: Vec<String> = video_ids
+        .iter()
+        .filter(|id| !items.iter().any(|i| i.video.as_ref() == Some(*id)))
+        .cloned()
+        .collect();
+
+    if live && !missing_ids.is_empty() {
+        let brightcove_access_token = brightcove_access_token.lock().await.clone();
+
+        match brightcove::get_video_views(&brightcove_access_token, &missing_ids).await {
+            Ok(response) => items.extend(response.items),
+            Err(e) => log::error!(target: "analytics", "live views: {}", e),
+        }
+    }
+
+    // same order as requested
+    items.sort_by_key(|i| video_ids.iter().position(|id| i.video.as_ref() == Some(id)));
+
+    Ok(Json(brightcove::analytics::VideosResponse {
+        item_count: items.len() as u32,
+        items,
+    }))
+}
+
+/// `?videos=id1,id2` restricts the breakdown to those videos, otherwise the whole account
+/// is summed.
+async fn analytics_show(
+    pool: Extension<SqlitePool>,
+    access: Extension<api_keys::Access>,
+    dimension: Path<brightcove::analytics::Dimension>,
+    params: Query<HashMap<String, String>>,
+) -> Result<Json<brightcove::analytics::SummaryResponse>, StatusCode> {
+    access.require(api_keys::Scope::VideoViews)?;
+
+    let video_ids = video_ids_param(&params)?;
+
+    match db::get_video_analytics(&pool, *dimension, &video_ids).await {
+        Ok(summary) => Ok(Json(summary)),
+        Err(e) => {
+            log::error!(target: "analytics", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn unix_time_in_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+/// `?videos=id1,id2`, `400 Bad Request` for an id that isn't a Brightcove (numeric) id or
+/// past `MAX_VIDEO_IDS` ids.
+fn video_ids_param(params: &HashMap<String, String>) -> Result<Vec<String>, StatusCode> {
+    let video_ids: Vec<String> = match params.get("videos") {
+        Some(videos) => videos
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect(),
+        None => vec![],
+    };
+
+    let valid = video_ids.len() <= MAX_VIDEO_IDS
+        && video_ids
+            .iter()
+            .all(|id| id.chars().all(|c| c.is_ascii_digit()));
+
+    match valid {
+        true => Ok(video_ids),
+        false => Err(StatusCode::BAD_REQUEST),
+    }
 }
 
 #[test]
@@ -298,8 +1832,34 @@ fn deserialize_player_response() {
                     terzo: Some("CAPITAN SPAV".to_string()),
                     ippodromo: "FIRENZE".to_string(),
                 },
-                video_views: 1
+                video_views: Some(1),
+                poster: None,
+                duration: None,
+                sources: vec![],
+                text_tracks: vec![],
             }]
         }
     );
 }
+
+#[test]
+fn parse_video_ids_param() {
+    let params = |videos: &str| HashMap::from([("videos".to_string(), videos.to_string())]);
+
+    assert_eq!(
+        video_ids_param(&params("6301819598001,,6301819238001")),
+        Ok(vec![
+            "6301819598001".to_string(),
+            "6301819238001".to_string()
+        ])
+    );
+    assert_eq!(video_ids_param(&HashMap::new()), Ok(vec![]));
+    assert_eq!(
+        video_ids_param(&params("6301819598001&where=x")),
+        Err(StatusCode::BAD_REQUEST)
+    );
+    assert_eq!(
+        video_ids_param(&params(&vec!["1"; MAX_VIDEO_IDS + 1].join(","))),
+        Err(StatusCode::BAD_REQUEST)
+    );
+}
@@ -0,0 +1,531 @@
+//! Prometheus metrics, served by `/metrics` in the text exposition format.
+//!
+//! The requests, the Brightcove calls and the syncs record into a process wide registry as
+//! they happen. The token age, the read pool, the cache and the tasks are read at scrape time.
+
+use axum::{
+    extract::{Extension, MatchedPath},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use http::{header, HeaderValue};
+use sqlx::sqlite::SqlitePool;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::cache::VideoCache;
+use crate::supervisor::Supervisor;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const SYNC_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+/// Name, type and help of every metric, in the order they're exposed.
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "http_requests_total",
+        "counter",
+        "HTTP requests by route, method and status.",
+    ),
+    (
+        "http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency by route and method.",
+    ),
+    (
+        "brightcove_requests_total",
+        "counter",
+        "Brightcove API calls by API.",
+    ),
+    (
+        "brightcove_request_errors_total",
+        "counter",
+        "Brightcove API calls that failed or weren't 2xx, by API.",
+    ),
+    (
+        "brightcove_request_duration_seconds",
+        "histogram",
+        "Brightcove API call latency by API.",
+    ),
+    (
+        "sync_duration_seconds",
+        "histogram",
+        "Duration of the sync passes by task and result.",
+    ),
+    (
+        "sync_videos_inserted_total",
+        "counter",
+        "Videos inserted by the video sync.",
+    ),
+    (
+        "sync_views_updated_total",
+        "counter",
+        "Videos whose views were updated by the views sync.",
+    ),
+    (
+        "brightcove_token_age_seconds",
+        "gauge",
+        "Time since the Brightcove token was last refreshed.",
+    ),
+    (
+        "sqlite_read_pool_connections",
+        "gauge",
+        "Connections of the read pool by state.",
+    ),
+    (
+        "sqlite_read_pool_max_connections",
+        "gauge",
+        "Size limit of the read pool.",
+    ),
+    (
+        "video_cache_entries",
+        "gauge",
+        "Entries in the video cache.",
+    ),
+    (
+        "video_cache_bytes",
+        "gauge",
+        "Approximate size of the video cache.",
+    ),
+    ("video_cache_hits_total", "counter", "Video cache hits."),
+    ("video_cache_misses_total", "counter", "Video cache misses."),
+    (
+        "video_cache_evictions_total",
+        "counter",
+        "Video cache entries evicted for room.",
+    ),
+    (
+        "video_cache_invalidations_total",
+        "counter",
+        "Video cache entries dropped by the syncs.",
+    ),
+    (
+        "task_running",
+        "gauge",
+        "Whether the background task is running.",
+    ),
+    (
+        "task_restarts_total",
+        "counter",
+        "Restarts of the background task by the supervisor.",
+    ),
+    (
+        "task_last_success_timestamp_seconds",
+        "gauge",
+        "Unix time of the last successful pass of the task.",
+    ),
+];
+
+/// A metric name and its rendered labels, e.g. `{api="oauth"}`.
+type Series = (&'static str, String);
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    /// cumulative, one per bucket
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Registry {
+    counters: BTreeMap<Series, f64>,
+    gauges: BTreeMap<Series, f64>,
+    histograms: BTreeMap<Series, Histogram>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    counters: BTreeMap::new(),
+    gauges: BTreeMap::new(),
+    histograms: BTreeMap::new(),
+});
+
+fn labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+impl Registry {
+    fn increment(&mut self, name: &'static str, labels: String, by: f64) {
+        *self.counters.entry((name, labels)).or_default() += by;
+    }
+
+    fn set(&mut self, name: &'static str, labels: String, value: f64) {
+        self.gauges.insert((name, labels), value);
+    }
+
+    fn observe(&mut self, name: &'static str, labels: String, buckets: &'static [f64], value: f64) {
+        let histogram = self
+            .histograms
+            .entry((name, labels))
+            .or_insert_with(|| Histogram {
+                buckets,
+                counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+
+        for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, kind, help) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+            let values = match *kind {
+                "counter" => &self.counters,
+                _ => &self.gauges,
+            };
+            for ((_, labels), value) in values.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+
+            for ((_, labels), histogram) in self.histograms.iter().filter(|((n, _), _)| n == name) {
+                // the bucket label goes after the others
+                let prefix = match labels.strip_suffix('}') {
+                    Some(labels) => format!("{},", labels),
+                    None => "{".to_string(),
+                };
+                for (bound, count) in histogram.buckets.iter().zip(&histogram.counts) {
+                    let _ = writeln!(out, "{}_bucket{}le=\"{}\"}} {}", name, prefix, bound, count);
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{}le=\"+Inf\"}} {}",
+                    name, prefix, histogram.count
+                );
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+            }
+        }
+
+        out
+    }
+}
+
+pub fn increment(name: &'static str, label_values: &[(&str, &str)], by: u64) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .increment(name, labels(label_values), by as f64);
+}
+
+fn observe(
+    name: &'static str,
+    label_values: &[(&str, &str)],
+    buckets: &'static [f64],
+    seconds: f64,
+) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .observe(name, labels(label_values), buckets, seconds);
+}
+
+/// Records how long a pass of the `task` sync took since `started`, and whether it succeeded.
+pub fn sync_pass(task: &str, started: Instant, success: bool) {
+    let result = match success {
+        true => "success",
+        false => "failure",
+    };
+
+    observe(
+        "sync_duration_seconds",
+        &[("task", task), ("result", result)],
+        SYNC_BUCKETS,
+        started.elapsed().as_secs_f64(),
+    );
+}
+
+/// Sends a Brightcove API call, recording it under `api` (`oauth`, `playback`, `cms` or
+/// `analytics`). Responses that aren't 2xx count as errors.
+pub async fn brightcove_call(
+    api: &str,
+    request: impl Future<Output = reqwest::Result<reqwest::Response>>,
+) -> reqwest::Result<reqwest::Response> {
+    let started = Instant::now();
+    let res = request.await;
+
+    increment("brightcove_requests_total", &[("api", api)], 1);
+    if !matches!(&res, Ok(res) if res.status().is_success()) {
+        increment("brightcove_request_errors_total", &[("api", api)], 1);
+    }
+    observe(
+        "brightcove_request_duration_seconds",
+        &[("api", api)],
+        LATENCY_BUCKETS,
+        started.elapsed().as_secs_f64(),
+    );
+
+    res
+}
+
+/// Route matched inside a nested router, passed out in the response extensions by
+/// `matched_route`.
+#[derive(Debug, Clone)]
+struct Route(String);
+
+/// Middleware of the nested routers: outside of them the matched path only goes up to the
+/// nest, e.g. `/api/v1/*`, so their route is handed to `track_requests` in the response.
+pub async fn matched_route<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| Route(path.as_str().to_string()));
+
+    let mut res = next.run(req).await;
+    if let Some(route) = route {
+        res.extensions_mut().insert(route);
+    }
+
+    res
+}
+
+/// Middleware counting the requests and their latency by route. Requests that don't match a
+/// route share the `unmatched` route, their paths would make unbounded series. The routes of
+/// the nested routers need `matched_route` layered on them.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    // a nested router matches `<nest>/*__private__axum_nest_tail_param` here
+    let matched = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .filter(|path| !path.ends_with("*__private__axum_nest_tail_param"));
+    let method = req.method().to_string();
+
+    let started = Instant::now();
+    let res = next.run(req).await;
+
+    let route = match res.extensions().get::<Route>() {
+        Some(route) => route.0.clone(),
+        None => matched.unwrap_or_else(|| "unmatched".to_string()),
+    };
+
+    increment(
+        "http_requests_total",
+        &[
+            ("route", &route),
+            ("method", &method),
+            ("status", res.status().as_str()),
+        ],
+        1,
+    );
+    observe(
+        "http_request_duration_seconds",
+        &[("route", &route), ("method", &method)],
+        LATENCY_BUCKETS,
+        started.elapsed().as_secs_f64(),
+    );
+
+    res
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    pool: SqlitePool,
+    pool_max_connections: u32,
+    supervisor: Supervisor,
+    video_cache: Arc<VideoCache>,
+}
+
+pub fn router(
+    pool: SqlitePool,
+    pool_max_connections: u32,
+    supervisor: Supervisor,
+    video_cache: Arc<VideoCache>,
+) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .layer(Extension(MetricsState {
+            pool,
+            pool_max_connections,
+            supervisor,
+            video_cache,
+        }))
+}
+
+async fn metrics(state: Extension<MetricsState>) -> impl IntoResponse {
+    let mut registry = REGISTRY.lock().unwrap().clone();
+    let now = crate::unix_time_in_ms();
+    let tasks = state.supervisor.states();
+
+    if let Some(at) = tasks.get("access_token").and_then(|t| t.last_success_at) {
+        registry.set(
+            "brightcove_token_age_seconds",
+            String::new(),
+            (now - at) as f64 / 1000.0,
+        );
+    }
+
+    let (size, idle) = (state.pool.size(), state.pool.num_idle() as u32);
+    registry.set(
+        "sqlite_read_pool_connections",
+        labels(&[("state", "idle")]),
+        idle as f64,
+    );
+    registry.set(
+        "sqlite_read_pool_connections",
+        labels(&[("state", "in_use")]),
+        size.saturating_sub(idle) as f64,
+    );
+    registry.set(
+        "sqlite_read_pool_max_connections",
+        String::new(),
+        state.pool_max_connections as f64,
+    );
+
+    let cache = state.video_cache.stats();
+    registry.set("video_cache_entries", String::new(), cache.entries as f64);
+    registry.set("video_cache_bytes", String::new(), cache.bytes as f64);
+    registry.increment("video_cache_hits_total", String::new(), cache.hits as f64);
+    registry.increment(
+        "video_cache_misses_total",
+        String::new(),
+        cache.misses as f64,
+    );
+    registry.increment(
+        "video_cache_evictions_total",
+        String::new(),
+        cache.evictions as f64,
+    );
+    registry.increment(
+        "video_cache_invalidations_total",
+        String::new(),
+        cache.invalidations as f64,
+    );
+
+    for (name, task) in &tasks {
+        let task_labels = labels(&[("task", name)]);
+        registry.set(
+            "task_running",
+            task_labels.clone(),
+            task.running as u8 as f64,
+        );
+        registry.increment(
+            "task_restarts_total",
+            task_labels.clone(),
+            task.restarts as f64,
+        );
+        if let Some(at) = task.last_success_at {
+            registry.set(
+                "task_last_success_timestamp_seconds",
+                task_labels,
+                at as f64 / 1000.0,
+            );
+        }
+    }
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        registry.render(),
+    )
+}
+
+#[test]
+fn render_text_format() {
+    let mut registry = Registry::default();
+    registry.increment("sync_videos_inserted_total", String::new(), 3.0);
+    registry.increment(
+        "brightcove_requests_total",
+        labels(&[("api", "oauth")]),
+        1.0,
+    );
+    registry.increment("brightcove_requests_total", labels(&[("api", "cms")]), 2.0);
+    registry.set("task_running", labels(&[("task", "say \"hi\"")]), 1.0);
+    registry.observe(
+        "sync_duration_seconds",
+        labels(&[("task", "sync_views")]),
+        SYNC_BUCKETS,
+        7.5,
+    );
+    registry.observe(
+        "sync_duration_seconds",
+        labels(&[("task", "sync_views")]),
+        SYNC_BUCKETS,
+        0.5,
+    );
+
+    let text = registry.render();
+
+    assert!(
+        text.contains("# TYPE sync_videos_inserted_total counter\nsync_videos_inserted_total 3\n")
+    );
+    assert!(text.contains(
+        "brightcove_requests_total{api=\"cms\"} 2\nbrightcove_requests_total{api=\"oauth\"} 1\n"
+    ));
+    assert!(text.contains("task_running{task=\"say \\\"hi\\\"\"} 1\n"));
+    assert!(text.contains("sync_duration_seconds_bucket{task=\"sync_views\",le=\"1\"} 1\n"));
+    assert!(text.contains("sync_duration_seconds_bucket{task=\"sync_views\",le=\"10\"} 2\n"));
+    assert!(text.contains("sync_duration_seconds_bucket{task=\"sync_views\",le=\"+Inf\"} 2\n"));
+    assert!(text.contains("sync_duration_seconds_sum{task=\"sync_views\"} 8\n"));
+    assert!(text.contains("# TYPE http_requests_total counter\n# HELP"));
+}
+
+#[tokio::test]
+async fn track_nested_routes() {
+    use axum::middleware::from_fn;
+
+    let api = Router::new()
+        .route("/items/:id", get(|| async { "item" }))
+        .layer(from_fn(matched_route));
+    let app = Router::new()
+        .route("/health", get(|| async { "ok" }))
+        .nest("/nested-test", api)
+        .layer(from_fn(track_requests));
+
+    let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    for path in ["/nested-test/items/1", "/nested-test/nope", "/health"] {
+        reqwest::get(format!("http://{}{}", addr, path))
+            .await
+            .unwrap();
+    }
+
+    let rendered = REGISTRY.lock().unwrap().render();
+    let requests = |route: &str| {
+        rendered.lines().any(|line| {
+            line.starts_with("http_requests_total{")
+                && line.contains(&format!(r#"route="{}""#, route))
+        })
+    };
+    assert!(requests("/nested-test/items/:id"));
+    assert!(requests("/health"));
+    assert!(requests("unmatched"));
+    assert!(!rendered.contains("nest_tail_param"));
+}
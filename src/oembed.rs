@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Extension, Query},
+    response::Json,
+    routing::get,
+    Router,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use crate::api_keys::Access;
+use crate::brightcove::Video;
+use crate::db;
+use crate::site::{escape_xml, player_url, VideoPages};
+
+/// Size of the embedded player when the consumer doesn't ask for a smaller one.
+const PLAYER_SIZE: (u32, u32) = (640, 360);
+
+#[derive(Debug, Deserialize)]
+struct OembedParams {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+/// oEmbed `video` response. There's no thumbnail: oEmbed wants its size with it, and the
+/// Playback API doesn't tell the size of the poster and thumbnail images.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OembedResponse {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub version: &'static str,
+    pub title: String,
+    pub provider_name: String,
+    pub provider_url: String,
+    pub html: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// oEmbed provider of the video pages: `/oembed?url=<VIDEO_PAGE_URL of a video>&format=json`.
+/// Without `VIDEO_PAGE_URL` it answers `501`.
+pub fn router(pool: SqlitePool, anonymous: Access) -> Router {
+    let video_pages = VideoPages::from_env();
+    if video_pages.is_none() {
+        log::info!("VIDEO_PAGE_URL not set, oEmbed disabled");
+    }
+
+    Router::new()
+        .route("/oembed", get(oembed))
+        .layer(Extension(pool))
+        .layer(Extension(anonymous))
+        .layer(Extension(video_pages))
+}
+
+async fn oembed(
+    pool: Extension<SqlitePool>,
+    access: Extension<Access>,
+    video_pages: Extension<Option<VideoPages>>,
+    params: Query<OembedParams>,
+) -> Result<Json<OembedResponse>, StatusCode> {
+    let video_pages = video_pages.as_ref().ok_or(StatusCode::NOT_IMPLEMENTED)?;
+
+    // json is the only format provided, oEmbed wants a 501 for the others
+    if !matches!(params.format.as_deref(), None | Some("json")) {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+
+    let video_id = video_id(video_pages.template(), &params.url).ok_or(StatusCode::NOT_FOUND)?;
+
+    match db::get_video(&pool, video_id).await {
+        Ok(Some(mut video)) => {
+            access.redact([&mut video]);
+            Ok(Json(response(
+                video_pages,
+                &video,
+                params.maxwidth,
+                params.maxheight,
+            )))
+        }
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            log::error!(target: "oembed", "{}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The `{id}` of `page_url_template` in `url`. The query and fragment of `url` are ignored,
+/// as is `http` in place of `https`.
+fn video_id<'a>(page_url_template: &str, url: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = page_url_template.split_once("{id}")?;
+
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let prefix = prefix
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let url = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+
+    let id = url.strip_prefix(prefix)?.strip_suffix(suffix)?;
+
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    valid.then_some(id)
+}
+
+/// Scales `size` down, keeping its aspect ratio, to fit `maxwidth` and `maxheight`. Neither
+/// side goes below 1.
+fn fit(size: (u32, u32), maxwidth: Option<u32>, maxheight: Option<u32>) -> (u32, u32) {
+    let (mut width, mut height) = size;
+
+    if let Some(maxwidth) = maxwidth.filter(|&m| m > 0 && m < width) {
+        height = height * maxwidth / width;
+        width = maxwidth;
+    }
+    if let Some(maxheight) = maxheight.filter(|&m| m > 0 && m < height) {
+        width = width * maxheight / height;
+        height = maxheight;
+    }
+
+    (width.max(1), height.max(1))
+}
+
+fn response(
+    video_pages: &VideoPages,
+    video: &Video,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+) -> OembedResponse {
+    let (width, height) = fit(PLAYER_SIZE, maxwidth, maxheight);
+
+    let html = format!(
+        r#"<iframe src="{}" width="{}" height="{}" title="{}" allow="encrypted-media; fullscreen" allowfullscreen frameborder="0"></iframe>"#,
+        escape_xml(&player_url(&video.id)),
+        width,
+        height,
+        escape_xml(&video.name)
+    );
+
+    OembedResponse {
+        kind: "video",
+        version: "1.0",
+        title: video.name.clone(),
+        provider_name: video_pages
+            .site_url()
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .to_string(),
+        provider_url: video_pages.site_url().to_string(),
+        html,
+        width,
+        height,
+    }
+}
+
+#[test]
+fn resolve_page_urls() {
+    let template = "https://www.example.com/video/{id}";
+
+    assert_eq!(
+        video_id(template, "https://www.example.com/video/6301819598001"),
+        Some("6301819598001")
+    );
+    assert_eq!(
+        video_id(
+            template,
+            "http://www.example.com/video/6301819598001?utm=x#t=10"
+        ),
+        Some("6301819598001")
+    );
+    assert_eq!(video_id(template, "https://www.example.com/video/"), None);
+    assert_eq!(
+        video_id(template, "https://www.example.com/video/1/2"),
+        None
+    );
+    assert_eq!(video_id(template, "https://www.other.com/video/1"), None);
+    assert_eq!(
+        video_id(
+            "https://www.example.com/corse/{id}.html",
+            "https://www.example.com/corse/42.html"
+        ),
+        Some("42")
+    );
+
+    assert_eq!(fit((640, 360), Some(320), None), (320, 180));
+    assert_eq!(fit((640, 360), Some(1000), Some(180)), (320, 180));
+    assert_eq!(fit((640, 360), None, None), (640, 360));
+    assert_eq!(fit((640, 360), Some(1), None), (1, 1));
+    assert_eq!(fit((640, 360), Some(1000), Some(1)), (1, 1));
+}
@@ -0,0 +1,147 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+
+/// Signs the RS256 JWTs Brightcove Playback Rights checks on every playback request.
+/// The public half of the key has to be registered with the Playback Rights API.
+pub struct PlaybackRights {
+    key: PKey<Private>,
+    key_id: Option<String>,
+    ttl: u64,
+    climit: Option<u32>,
+    dlimit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Claims {
+    pub accid: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// the only video the token can play
+    pub conid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    /// concurrent streams per `uid`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub climit: Option<u32>,
+    /// devices per `uid`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dlimit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+struct Header<'a> {
+    alg: &'a str,
+    typ: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<&'a str>,
+}
+
+impl PlaybackRights {
+    /// Reads the PEM key at `PLAYBACK_RIGHTS_PRIVATE_KEY_PATH`. Without it tokens can't be
+    /// generated and `None` is returned.
+    ///
+    /// - `PLAYBACK_RIGHTS_KEY_ID`: optional `kid` header
+    /// - `PLAYBACK_RIGHTS_TOKEN_TTL_IN_S`: token lifetime, 300 by default
+    /// - `PLAYBACK_RIGHTS_CLIMIT`, `PLAYBACK_RIGHTS_DLIMIT`: optional stream and device limits
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let key_path = match std::env::var("PLAYBACK_RIGHTS_PRIVATE_KEY_PATH") {
+            Ok(key_path) => key_path,
+            Err(_) => return Ok(None),
+        };
+
+        let pem = std::fs::read(&key_path)?;
+
+        Ok(Some(PlaybackRights {
+            key: PKey::private_key_from_pem(&pem)?,
+            key_id: std::env::var("PLAYBACK_RIGHTS_KEY_ID").ok(),
+            ttl: crate::env_var("PLAYBACK_RIGHTS_TOKEN_TTL_IN_S", 300)?,
+            climit: match std::env::var("PLAYBACK_RIGHTS_CLIMIT") {
+                Ok(climit) => Some(climit.parse()?),
+                Err(_) => None,
+            },
+            dlimit: match std::env::var("PLAYBACK_RIGHTS_DLIMIT") {
+                Ok(dlimit) => Some(dlimit.parse()?),
+                Err(_) => None,
+            },
+        }))
+    }
+
+    pub fn claims(&self, video_id: &str, uid: Option<String>, now: u64) -> Claims {
+        Claims {
+            accid: dotenv!("ACCOUNT_ID").to_string(),
+            iat: now,
+            exp: now + self.ttl,
+            conid: video_id.to_string(),
+            uid,
+            climit: self.climit,
+            dlimit: self.dlimit,
+        }
+    }
+
+    pub fn sign(&self, claims: &Claims) -> anyhow::Result<String> {
+        let header = Header {
+            alg: "RS256",
+            typ: "JWT",
+            kid: self.key_id.as_deref(),
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            base64_url(&serde_json::to_vec(&header)?),
+            base64_url(&serde_json::to_vec(claims)?)
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(signing_input.as_bytes())?;
+        let signature = signer.sign_to_vec()?;
+
+        Ok(format!("{}.{}", signing_input, base64_url(&signature)))
+    }
+}
+
+/// base64url without padding, as JWTs want it
+fn base64_url(bytes: &[u8]) -> String {
+    openssl::base64::encode_block(bytes)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[test]
+fn sign_playback_token() {
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let playback_rights = PlaybackRights {
+        key: key.clone(),
+        key_id: None,
+        ttl: 60,
+        climit: Some(2),
+        dlimit: None,
+    };
+
+    let claims = playback_rights.claims("6301819598001", Some("user-1".to_string()), 1000);
+    let token = playback_rights.sign(&claims).unwrap();
+
+    let parts: Vec<&str> = token.split('.').collect();
+    assert_eq!(parts.len(), 3);
+    assert!(!token.contains('=') && !token.contains('+') && !token.contains('/'));
+
+    let signature = openssl::base64::decode_block(
+        &(parts[2].replace('-', "+").replace('_', "/") + &"=".repeat((4 - parts[2].len() % 4) % 4)),
+    )
+    .unwrap();
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+    verifier
+        .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+        .unwrap();
+    assert!(verifier.verify(&signature).unwrap());
+
+    assert_eq!(claims.exp, 1060);
+    assert_eq!(claims.conid, "6301819598001");
+    assert_eq!(claims.climit, Some(2));
+}
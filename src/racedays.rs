@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+
+use crate::brightcove::Video;
+
+/// Race days are exposed as `YYYY-MM-DD`, the `data` custom field stores them as `YYYY/MM/DD`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RacedaysResponse {
+    pub count: u32,
+    pub racedays: Vec<Raceday>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Raceday {
+    pub date: String,
+    pub video_count: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RacedayResponse {
+    pub date: String,
+    pub racecourses: Vec<Racecourse>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Racecourse {
+    pub ippodromo: String,
+    pub races: Vec<Race>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Race {
+    pub numero_corsa: String,
+    pub videos: Vec<Video>,
+}
+
+/// A race with every video of it (full race, finish replay, photo finish...).
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RaceResponse {
+    #[serde(flatten)]
+    pub race: crate::db::RaceRow,
+    pub videos: Vec<Video>,
+}
+
+/// `2022-03-20` -> `2022/03/20`, `None` if `date` isn't a `YYYY-MM-DD` date.
+pub fn to_data(date: &str) -> Option<String> {
+    let parts: Vec<&str> = date.split('-').collect();
+
+    match parts.as_slice() {
+        [y, m, d]
+            if y.len() == 4
+                && m.len() == 2
+                && d.len() == 2
+                && parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit())) =>
+        {
+            Some(format!("{}/{}/{}", y, m, d))
+        }
+        _ => None,
+    }
+}
+
+/// `2022/03/20` -> `2022-03-20`
+pub fn from_data(data: &str) -> String {
+    data.replace('/', "-")
+}
+
+/// Groups the videos of a day by racecourse (sorted by name) and race (sorted by the
+/// `numero_corsa` number, `"2"` before `"10"`, and `"02"` is the same race as `"2"`).
+pub fn group(videos: Vec<Video>) -> Vec<Racecourse> {
+    let mut racecourses: Vec<Racecourse> = Vec::new();
+
+    for video in videos {
+        let ippodromo = video.custom_fields.ippodromo.clone();
+        let numero_corsa = video.custom_fields.numero_corsa.clone();
+
+        let racecourse = match racecourses.iter().position(|r| r.ippodromo == ippodromo) {
+            Some(i) => &mut racecourses[i],
+            None => {
+                racecourses.push(Racecourse {
+                    ippodromo,
+                    races: vec![],
+                });
+                racecourses.last_mut().unwrap()
+            }
+        };
+
+        match racecourse
+            .races
+            .iter_mut()
+            .find(|r| race_number(&r.numero_corsa) == race_number(&numero_corsa))
+        {
+            Some(race) => race.videos.push(video),
+            None => racecourse.races.push(Race {
+                numero_corsa,
+                videos: vec![video],
+            }),
+        }
+    }
+
+    racecourses.sort_by(|a, b| a.ippodromo.cmp(&b.ippodromo));
+    for racecourse in racecourses.iter_mut() {
+        racecourse
+            .races
+            .sort_by_key(|r| race_number(&r.numero_corsa));
+    }
+
+    racecourses
+}
+
+/// Sort key of `numero_corsa`, which is a zero padded number most of the time. Anything else
+/// goes last.
+pub fn race_number(numero_corsa: &str) -> (u32, String) {
+    match numero_corsa.trim().parse::<u32>() {
+        Ok(n) => (n, String::new()),
+        Err(_) => (u32::MAX, numero_corsa.to_string()),
+    }
+}
+
+/// `numero_corsa` as the races are keyed by: the number without its zero padding, `"02"` and
+/// `"2"` are the same race as in `group`.
+pub fn race_key(numero_corsa: &str) -> String {
+    match numero_corsa.trim().parse::<u32>() {
+        Ok(n) => n.to_string(),
+        Err(_) => numero_corsa.trim().to_string(),
+    }
+}
+
+#[test]
+fn group_videos_by_racecourse_and_race() {
+    let video = |id: &str, ippodromo: &str, numero_corsa: &str| Video {
+        id: id.to_string(),
+        name: id.to_string(),
+        thumbnail: String::new(),
+        custom_fields: crate::brightcove::VideoCustomFields {
+            numero_corsa: numero_corsa.to_string(),
+            data: "2022/03/20".to_string(),
+            tipologia: None,
+            cavalli: None,
+            fantini: None,
+            primo: None,
+            secondo: None,
+            terzo: None,
+            ippodromo: ippodromo.to_string(),
+        },
+        video_views: None,
+        poster: None,
+        duration: None,
+        sources: vec![],
+        text_tracks: vec![],
+    };
+
+    let racecourses = group(vec![
+        video("a", "FIRENZE", "10"),
+        video("b", "FIRENZE", "2"),
+        video("c", "AGNANO", "01"),
+        video("d", "FIRENZE", "02"),
+    ]);
+
+    assert_eq!(racecourses.len(), 2);
+    assert_eq!(racecourses[0].ippodromo, "AGNANO");
+
+    let firenze: Vec<(&str, usize)> = racecourses[1]
+        .races
+        .iter()
+        .map(|r| (r.numero_corsa.as_str(), r.videos.len()))
+        .collect();
+    assert_eq!(firenze, vec![("2", 2), ("10", 1)]);
+
+    assert_eq!(to_data("2022-03-20"), Some("2022/03/20".to_string()));
+    assert_eq!(to_data("2022/03/20"), None);
+}
+
+#[test]
+fn race_keys() {
+    assert_eq!(race_key("02"), "2");
+    assert_eq!(race_key(" 2 "), "2");
+    assert_eq!(race_key("10"), "10");
+    assert_eq!(race_key("2bis"), "2bis");
+    assert_eq!(race_key(""), "");
+}
@@ -0,0 +1,333 @@
+//! Pieces shared by what's generated for the public website: feeds, sitemaps, embeds.
+
+use serde::Serialize;
+
+use crate::brightcove::{Delivery, Source, Video};
+
+/// Pages of the videos on the website, `VIDEO_PAGE_URL` with `{id}` in place of the
+/// Brightcove video id, e.g. `https://www.example.com/video/{id}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoPages {
+    template: String,
+}
+
+impl VideoPages {
+    pub fn new(template: &str) -> Self {
+        VideoPages {
+            template: template.to_string(),
+        }
+    }
+
+    /// `None` without `VIDEO_PAGE_URL`, the routes linking to the website answer `501` then.
+    pub fn from_env() -> Option<Self> {
+        std::env::var("VIDEO_PAGE_URL")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|template| VideoPages::new(&template))
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Page of the video.
+    pub fn url(&self, bc_video_id: &str) -> String {
+        self.template.replace("{id}", bc_video_id)
+    }
+
+    /// Scheme and host of the pages.
+    pub fn site_url(&self) -> &str {
+        let url = self.template.as_str();
+
+        match url.find("://") {
+            Some(scheme_end) => match url[scheme_end + 3..].find('/') {
+                Some(host_end) => &url[..scheme_end + 3 + host_end],
+                None => url,
+            },
+            None => url,
+        }
+    }
+}
+
+/// Brightcove player page of the video, `BRIGHTCOVE_PLAYER_ID` or the account default player.
+pub fn player_url(bc_video_id: &str) -> String {
+    let player_id = std::env::var("BRIGHTCOVE_PLAYER_ID")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "default".to_string());
+
+    format!(
+        "https://players.brightcove.net/{}/{}_default/index.html?videoId={}",
+        dotenv!("ACCOUNT_ID"),
+        player_id,
+        bc_video_id
+    )
+}
+
+/// Race metadata of the video, one `label: value` per line.
+pub fn video_description(video: &Video) -> String {
+    let fields = &video.custom_fields;
+
+    [
+        ("Ippodromo", Some(&fields.ippodromo)),
+        ("Data", Some(&fields.data)),
+        ("Corsa", Some(&fields.numero_corsa)),
+        ("Tipologia", fields.tipologia.as_ref()),
+        ("Primo", fields.primo.as_ref()),
+        ("Secondo", fields.secondo.as_ref()),
+        ("Terzo", fields.terzo.as_ref()),
+        ("Cavalli", fields.cavalli.as_ref()),
+        ("Fantini", fields.fantini.as_ref()),
+    ]
+    .iter()
+    .filter_map(|(label, value)| value.map(|v| format!("{}: {}", label, v)))
+    .collect::<Vec<String>>()
+    .join("\n")
+}
+
+/// The mp4 rendition with the highest resolution, or the HLS stream if there's no mp4.
+pub fn content_source(video: &Video) -> Option<&Source> {
+    video
+        .sources
+        .iter()
+        .filter(|s| s.src.is_some() && Delivery::Mp4.matches(s))
+        .max_by_key(|s| s.height.unwrap_or(0))
+        .or_else(|| {
+            video
+                .sources
+                .iter()
+                .find(|s| s.src.is_some() && Delivery::Hls.matches(s))
+        })
+}
+
+/// schema.org `VideoObject`, the JSON-LD search engines read video results from.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoObject {
+    #[serde(rename = "@context")]
+    pub context: &'static str,
+    #[serde(rename = "@type")]
+    pub kind: &'static str,
+    pub name: String,
+    pub description: String,
+    pub thumbnail_url: Vec<String>,
+    pub upload_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_url: Option<String>,
+    pub embed_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interaction_statistic: Option<InteractionCounter>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InteractionCounter {
+    #[serde(rename = "@type")]
+    pub kind: &'static str,
+    pub interaction_type: &'static str,
+    pub user_interaction_count: u32,
+}
+
+impl From<&Video> for VideoObject {
+    fn from(video: &Video) -> Self {
+        let mut thumbnail_url = vec![video.thumbnail.clone()];
+        if let Some(poster) = &video.poster {
+            thumbnail_url.push(poster.clone());
+        }
+
+        VideoObject {
+            context: "https://schema.org",
+            kind: "VideoObject",
+            name: video.name.clone(),
+            description: video_description(video),
+            thumbnail_url,
+            upload_date: rfc3339_date(&video.custom_fields.data),
+            duration: video.duration.map(iso8601_duration),
+            content_url: content_source(video).and_then(|s| s.src.clone()),
+            embed_url: player_url(&video.id),
+            interaction_statistic: video.video_views.map(|views| InteractionCounter {
+                kind: "InteractionCounter",
+                interaction_type: "https://schema.org/WatchAction",
+                user_interaction_count: views,
+            }),
+        }
+    }
+}
+
+/// `PT1M35S` of a duration in milliseconds.
+pub fn iso8601_duration(duration_in_ms: u64) -> String {
+    let seconds = duration_in_ms / 1000;
+
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, s) => format!("PT{}S", s),
+        (0, m, s) => format!("PT{}M{}S", m, s),
+        (h, m, s) => format!("PT{}H{}M{}S", h, m, s),
+    }
+}
+
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `(year, month, day)` of a `YYYY/MM/DD` `data` custom field.
+pub fn parse_data(data: &str) -> Option<(i32, u32, u32)> {
+    let parts: Vec<&str> = data.split('/').collect();
+
+    match parts.as_slice() {
+        [y, m, d] => Some((y.parse().ok()?, m.parse().ok()?, d.parse().ok()?)),
+        _ => None,
+    }
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// `Sun, 20 Mar 2022 00:00:00 GMT` of a `data` day, the date format of RSS and of the HTTP
+/// headers.
+pub fn http_date(data: &str) -> Option<String> {
+    let (y, m, d) = parse_data(data)?;
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+
+    Some(http_date_from_unix(days_from_civil(y, m, d) * 86_400))
+}
+
+/// `Sun, 20 Mar 2022 12:30:00 GMT` of a unix time in seconds.
+pub fn http_date_from_unix(secs: i64) -> String {
+    let days = secs.div_euclid(86_400);
+    let time = secs.rem_euclid(86_400);
+    let (y, m, d) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],
+        d,
+        MONTHS[m as usize - 1],
+        y,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// `2022-03-20T12:30:00Z` of a unix time in seconds.
+pub fn rfc3339_from_unix(secs: i64) -> String {
+    let time = secs.rem_euclid(86_400);
+    let (y, m, d) = civil_from_days(secs.div_euclid(86_400));
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        y,
+        m,
+        d,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Unix time in seconds of a `Sun, 20 Mar 2022 12:30:00 GMT` date, the only format HTTP
+/// clients still send.
+pub fn parse_http_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+
+    match parts.as_slice() {
+        [_, d, month, y, time, "GMT"] => {
+            let m = MONTHS.iter().position(|name| name == month)? as u32 + 1;
+            let time: Vec<i64> = time
+                .split(':')
+                .map(|t| t.parse().ok())
+                .collect::<Option<Vec<i64>>>()?;
+            if time.len() != 3 {
+                return None;
+            }
+
+            Some(
+                days_from_civil(y.parse().ok()?, m, d.parse().ok()?) * 86_400
+                    + time[0] * 3600
+                    + time[1] * 60
+                    + time[2],
+            )
+        }
+        _ => None,
+    }
+}
+
+/// `2022-03-20T00:00:00Z`, the date format of Atom and schema.org.
+pub fn rfc3339_date(data: &str) -> Option<String> {
+    let (y, m, d) = parse_data(data)?;
+
+    Some(format!("{:04}-{:02}-{:02}T00:00:00Z", y, m, d))
+}
+
+/// Days since 1970-01-01 of a date, Howard Hinnant's algorithm.
+fn days_from_civil(y: i32, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y } as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (
+        if m <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        m,
+        d,
+    )
+}
+
+#[test]
+fn format_dates() {
+    assert_eq!(
+        http_date("2022/03/20"),
+        Some("Sun, 20 Mar 2022 00:00:00 GMT".to_string())
+    );
+    assert_eq!(
+        http_date("2024/02/29"),
+        Some("Thu, 29 Feb 2024 00:00:00 GMT".to_string())
+    );
+    assert_eq!(
+        rfc3339_date("2022/03/20"),
+        Some("2022-03-20T00:00:00Z".to_string())
+    );
+    assert_eq!(http_date("20/03/2022x"), None);
+    assert_eq!(
+        http_date_from_unix(1_647_779_400),
+        "Sun, 20 Mar 2022 12:30:00 GMT"
+    );
+    assert_eq!(rfc3339_from_unix(1_647_779_400), "2022-03-20T12:30:00Z");
+    assert_eq!(
+        parse_http_date("Sun, 20 Mar 2022 12:30:00 GMT"),
+        Some(1_647_779_400)
+    );
+    assert_eq!(parse_http_date("Sunday, 20-Mar-22 12:30:00 GMT"), None);
+    assert_eq!(iso8601_duration(95_400), "PT1M35S");
+    assert_eq!(iso8601_duration(3_605_000), "PT1H0M5S");
+    assert_eq!(escape_xml("A & B <C>"), "A &amp; B &lt;C&gt;");
+}
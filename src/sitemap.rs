@@ -0,0 +1,197 @@
+use axum::{
+    extract::{Extension, Query},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+
+use crate::api_keys::Access;
+use crate::brightcove::Video;
+use crate::db;
+use crate::site::{
+    content_source, escape_xml, player_url, rfc3339_date, video_description, VideoPages,
+};
+
+/// Most urls a sitemap file can list, past it the sitemap becomes an index of pages.
+const SITEMAP_URLS: u32 = 50_000;
+const SITEMAP_MAX_AGE_IN_S: u32 = 3600;
+/// Longest `video:description` allowed.
+const DESCRIPTION_MAX_CHARS: usize = 2048;
+
+/// Google video sitemap of every video, `/sitemap-videos.xml`.
+///
+/// The index links the pages as `<website>/sitemap-videos.xml?page=N`: search engines only
+/// accept sitemaps from the host of their urls, so the website is expected to serve this
+/// route at its root. It has no API key, so the view counts need `video_views` in
+/// `API_KEYS_ANONYMOUS_SCOPES`. Without `VIDEO_PAGE_URL` it answers `501`.
+pub fn router(pool: SqlitePool, anonymous: Access) -> Router {
+    let video_pages = VideoPages::from_env();
+    if video_pages.is_none() {
+        log::info!("VIDEO_PAGE_URL not set, sitemap disabled");
+    }
+
+    Router::new()
+        .route("/sitemap-videos.xml", get(sitemap_videos))
+        .layer(Extension(pool))
+        .layer(Extension(anonymous))
+        .layer(Extension(video_pages))
+}
+
+async fn sitemap_videos(
+    pool: Extension<SqlitePool>,
+    access: Extension<Access>,
+    video_pages: Extension<Option<VideoPages>>,
+    params: Query<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    let video_pages = video_pages.as_ref().ok_or(StatusCode::NOT_IMPLEMENTED)?;
+    let page = match params.get("page") {
+        Some(page) => match page.parse::<u32>() {
+            Ok(page) if page > 0 => Some(page),
+            _ => return Err(StatusCode::BAD_REQUEST),
+        },
+        None => None,
+    };
+
+    let count = db::get_video_count(&pool).await.map_err(|e| {
+        log::error!(target: "sitemap", "{}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let pages = count.div_ceil(SITEMAP_URLS);
+
+    let xml = match page {
+        None if pages > 1 => sitemap_index(video_pages, pages),
+        Some(page) if page > pages.max(1) => return Err(StatusCode::NOT_FOUND),
+        page => {
+            let offset = (page.unwrap_or(1) - 1) * SITEMAP_URLS;
+            let mut videos = db::get_videos_by_id(&pool, SITEMAP_URLS, offset)
+                .await
+                .map_err(|e| {
+                    log::error!(target: "sitemap", "{}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            access.redact(videos.iter_mut());
+            urlset(video_pages, &videos)
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&format!("public, max-age={}", SITEMAP_MAX_AGE_IN_S)).unwrap(),
+    );
+
+    Ok((StatusCode::OK, headers, xml).into_response())
+}
+
+fn sitemap_index(video_pages: &VideoPages, pages: u32) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push('\n');
+    xml.push_str(r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#);
+
+    for page in 1..=pages {
+        xml.push_str(&format!(
+            "<sitemap><loc>{}</loc></sitemap>",
+            escape_xml(&format!(
+                "{}/sitemap-videos.xml?page={}",
+                video_pages.site_url(),
+                page
+            ))
+        ));
+    }
+
+    xml.push_str("</sitemapindex>");
+    xml
+}
+
+fn urlset(video_pages: &VideoPages, videos: &[Video]) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push('\n');
+    xml.push_str(
+        r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:video="http://www.google.com/schemas/sitemap-video/1.1">"#,
+    );
+
+    for video in videos {
+        let description: String = video_description(video)
+            .chars()
+            .take(DESCRIPTION_MAX_CHARS)
+            .collect();
+
+        xml.push_str("<url>");
+        xml.push_str(&format!(
+            "<loc>{}</loc>",
+            escape_xml(&video_pages.url(&video.id))
+        ));
+        xml.push_str("<video:video>");
+        xml.push_str(&format!(
+            "<video:thumbnail_loc>{}</video:thumbnail_loc>",
+            escape_xml(&video.thumbnail)
+        ));
+        xml.push_str(&format!(
+            "<video:title>{}</video:title>",
+            escape_xml(&video.name)
+        ));
+        xml.push_str(&format!(
+            "<video:description>{}</video:description>",
+            escape_xml(&description)
+        ));
+        if let Some(src) = content_source(video).and_then(|s| s.src.as_ref()) {
+            xml.push_str(&format!(
+                "<video:content_loc>{}</video:content_loc>",
+                escape_xml(src)
+            ));
+        }
+        xml.push_str(&format!(
+            "<video:player_loc>{}</video:player_loc>",
+            escape_xml(&player_url(&video.id))
+        ));
+        if let Some(duration) = video.duration {
+            xml.push_str(&format!(
+                "<video:duration>{}</video:duration>",
+                (duration / 1000).max(1)
+            ));
+        }
+        if let Some(views) = video.video_views {
+            xml.push_str(&format!("<video:view_count>{}</video:view_count>", views));
+        }
+        if let Some(date) = rfc3339_date(&video.custom_fields.data) {
+            xml.push_str(&format!(
+                "<video:publication_date>{}</video:publication_date>",
+                date
+            ));
+        }
+        xml.push_str("</video:video>");
+        xml.push_str("</url>");
+    }
+
+    xml.push_str("</urlset>");
+    xml
+}
+
+#[test]
+fn video_sitemap() {
+    let video: Video = serde_json::from_str(
+        r#"{"id":"6301819598001","name":"Corsa 3","thumbnail":"https://cf-images.example.com/thumb.jpg","video_views":12,"poster":null,"duration":95000,
+        "custom_fields":{"numero_corsa":"3","data":"2022/03/20","tipologia":null,"cavalli":null,"fantini":null,"primo":null,"secondo":null,"terzo":null,"ippodromo":"FIRENZE"}}"#,
+    )
+    .unwrap();
+
+    let video_pages = VideoPages::new("https://www.example.com/video/{id}");
+    let xml = urlset(&video_pages, &[video]);
+    assert!(xml.contains("<loc>https://www.example.com/video/6301819598001</loc>"));
+    assert!(xml.contains("<video:title>Corsa 3</video:title>"));
+    assert!(xml.contains("<video:duration>95</video:duration>"));
+    assert!(xml.contains("<video:view_count>12</video:view_count>"));
+    assert!(xml.contains("<video:publication_date>2022-03-20T00:00:00Z</video:publication_date>"));
+    assert!(!xml.contains("<video:content_loc>"));
+
+    let index = sitemap_index(&video_pages, 3);
+    assert_eq!(index.matches("<sitemap>").count(), 3);
+    assert!(index.contains("<loc>https://www.example.com/sitemap-videos.xml?page=3</loc>"));
+}
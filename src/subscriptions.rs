@@ -0,0 +1,406 @@
+use futures::{StreamExt, TryStreamExt};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::db;
+use crate::writer::Writer;
+
+/// Deliveries are given up after this many attempts.
+const MAX_ATTEMPTS: u32 = 8;
+const DELIVERIES_PER_PASS: u32 = 50;
+/// Urls delivered to at once.
+const CONCURRENT_SUBSCRIBERS: usize = 8;
+/// How long the delivered and failed deliveries are kept.
+const DELIVERIES_RETENTION_IN_DAYS: i64 = 30;
+
+/// Events downstream systems can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    VideoCreated,
+    VideoUpdated,
+    VideoDeleted,
+    /// deactivated or out of its schedule in Brightcove
+    VideoUnavailable,
+    ViewsUpdated,
+}
+
+impl Event {
+    pub const ALL: [Event; 5] = [
+        Event::VideoCreated,
+        Event::VideoUpdated,
+        Event::VideoDeleted,
+        Event::VideoUnavailable,
+        Event::ViewsUpdated,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::VideoCreated => "video.created",
+            Event::VideoUpdated => "video.updated",
+            Event::VideoDeleted => "video.deleted",
+            Event::VideoUnavailable => "video.unavailable",
+            Event::ViewsUpdated => "views.updated",
+        }
+    }
+
+    pub fn parse(event: &str) -> Option<Event> {
+        Event::ALL.into_iter().find(|e| e.as_str() == event)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Envelope<'a, T: Serialize> {
+    event: &'a str,
+    created_at: i64,
+    data: &'a T,
+}
+
+/// Payload of `views.updated`.
+#[derive(Debug, Serialize)]
+pub struct ViewsUpdated {
+    pub sync: String,
+    pub videos_updated: u64,
+    pub synced_at: i64,
+}
+
+/// Queues `data` for every subscriber of `event`, `deliver_due` sends it.
+pub async fn enqueue<T: Serialize>(
+    conn: &mut sqlx::SqliteConnection,
+    event: Event,
+    data: &T,
+    now: i64,
+) -> anyhow::Result<u64> {
+    let payload = serde_json::to_string(&Envelope {
+        event: event.as_str(),
+        created_at: now,
+        data,
+    })?;
+
+    db::enqueue_webhook_deliveries(conn, event.as_str(), &payload, now).await
+}
+
+/// Subscribes `url` to `events`, returns the subscription with the secret its deliveries are
+/// signed with.
+pub async fn subscribe(
+    writer: &Writer,
+    url: String,
+    events: &[Event],
+) -> anyhow::Result<(db::WebhookSubscriptionRow, String)> {
+    let mut bytes = [0; 24];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let events = events
+        .iter()
+        .map(|e| e.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let now = crate::unix_time_in_ms();
+
+    let row = db::WebhookSubscriptionRow {
+        id: 0,
+        url,
+        events,
+        active: true,
+        created_at: now,
+    };
+
+    let mut saved = row.clone();
+    let saved_secret = secret.clone();
+    saved.id = writer
+        .run(move |conn| {
+            Box::pin(async move {
+                db::save_webhook_subscription(
+                    conn,
+                    &row.url,
+                    &saved_secret,
+                    &row.events,
+                    row.created_at,
+                )
+                .await
+            })
+        })
+        .await?;
+
+    Ok((saved, secret))
+}
+
+/// `X-Signature` header of `body`: `sha256=` and the hex HMAC-SHA256 keyed with the
+/// subscription secret.
+pub fn signature(secret: &str, body: &[u8]) -> anyhow::Result<String> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+
+    let hex: String = signer
+        .sign_to_vec()?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(format!("sha256={}", hex))
+}
+
+/// Delay before retrying a delivery attempted `attempts` times: 30s doubling up to an hour.
+/// `None` once `MAX_ATTEMPTS` is reached.
+pub fn retry_delay(attempts: u32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+
+    Some(Duration::from_secs(30 * 2_u64.pow(attempts - 1)).min(Duration::from_secs(3600)))
+}
+
+/// Sends the deliveries that are due, to `CONCURRENT_SUBSCRIBERS` urls at once and in order
+/// for each url. A url failing is left for the next pass, so a slow or dead subscriber holds
+/// the pass for one timeout only. Returns how many were attempted.
+pub async fn deliver_due(pool: &SqlitePool, writer: &Writer, now: i64) -> anyhow::Result<usize> {
+    writer
+        .run(move |conn| {
+            Box::pin(async move {
+                db::delete_webhook_deliveries_before(
+                    conn,
+                    now - DELIVERIES_RETENTION_IN_DAYS * 86_400_000,
+                )
+                .await
+            })
+        })
+        .await?;
+
+    let deliveries = {
+        let mut conn = pool.acquire().await?;
+        db::get_due_webhook_deliveries(&mut conn, now, DELIVERIES_PER_PASS).await?
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    let mut by_url: HashMap<String, Vec<db::DueDelivery>> = HashMap::new();
+    for delivery in deliveries {
+        by_url
+            .entry(delivery.url.clone())
+            .or_default()
+            .push(delivery);
+    }
+
+    futures::stream::iter(by_url.into_values())
+        .map(|deliveries| {
+            let (client, writer) = (client.clone(), writer.clone());
+            async move { deliver_in_order(&client, &writer, deliveries, now).await }
+        })
+        .buffer_unordered(CONCURRENT_SUBSCRIBERS)
+        .try_fold(0, |attempted, n| async move { Ok(attempted + n) })
+        .await
+}
+
+/// Sends the deliveries of a url until one fails. Returns how many were attempted.
+async fn deliver_in_order(
+    client: &reqwest::Client,
+    writer: &Writer,
+    deliveries: Vec<db::DueDelivery>,
+    now: i64,
+) -> anyhow::Result<usize> {
+    let mut attempted = 0;
+
+    for delivery in &deliveries {
+        attempted += 1;
+        if !deliver(client, writer, delivery, now).await? {
+            break;
+        }
+    }
+
+    Ok(attempted)
+}
+
+/// Sends a delivery and records the attempt. Returns whether it was delivered.
+async fn deliver(
+    client: &reqwest::Client,
+    writer: &Writer,
+    delivery: &db::DueDelivery,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let result = match signature(&delivery.secret, delivery.payload.as_bytes()) {
+        Ok(signature) => client
+            .post(&delivery.url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Event", &delivery.event)
+            .header("X-Webhook-Delivery", delivery.id.to_string())
+            .header("X-Signature", signature)
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+
+    let (status_code, error) = match &result {
+        Ok(res) if res.status().is_success() => (Some(res.status().as_u16()), None),
+        Ok(res) => (Some(res.status().as_u16()), Some(res.status().to_string())),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let delivered = error.is_none();
+    let attempts = delivery.attempts + 1;
+    let next_attempt_at = match delivered {
+        true => None,
+        false => retry_delay(attempts).map(|d| now + d.as_millis() as i64),
+    };
+
+    match (&error, next_attempt_at) {
+        (None, _) => {
+            log::debug!(target: "webhooks", "delivered {} to {}", delivery.id, delivery.url)
+        }
+        (Some(e), Some(_)) => log::info!(
+            target: "webhooks",
+            "delivery {} to {} failed ({}), will retry",
+            delivery.id,
+            delivery.url,
+            e
+        ),
+        (Some(e), None) => log::error!(
+            target: "webhooks",
+            "delivery {} to {} failed ({}), giving up after {} attempts",
+            delivery.id,
+            delivery.url,
+            e,
+            attempts
+        ),
+    }
+
+    let id = delivery.id;
+    writer
+        .run(move |conn| {
+            Box::pin(async move {
+                db::save_webhook_attempt(
+                    conn,
+                    id,
+                    delivered,
+                    status_code,
+                    error.as_deref(),
+                    next_attempt_at,
+                    now,
+                )
+                .await
+            })
+        })
+        .await?;
+
+    Ok(delivered)
+}
+
+#[test]
+fn sign_payload_and_back_off() {
+    assert_eq!(
+        signature("key", b"The quick brown fox jumps over the lazy dog").unwrap(),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+
+    assert_eq!(retry_delay(1), Some(Duration::from_secs(30)));
+    assert_eq!(retry_delay(3), Some(Duration::from_secs(120)));
+    assert_eq!(
+        retry_delay(MAX_ATTEMPTS - 1),
+        Some(Duration::from_secs(1920))
+    );
+    assert_eq!(retry_delay(MAX_ATTEMPTS), None);
+}
+
+#[test]
+fn parse_events() {
+    for event in Event::ALL {
+        assert_eq!(Event::parse(event.as_str()), Some(event));
+    }
+    assert_eq!(Event::parse("video.removed"), None);
+}
+
+#[tokio::test]
+async fn deliver_around_a_dead_subscriber() {
+    use axum::{routing::post, Router};
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+    use sqlx::ConnectOptions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!("deliveries-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let options = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new()
+        .connect_with(options.clone())
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    let (writer, _) = Writer::spawn(options.connect().await.unwrap());
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let app = Router::new().route(
+        "/hook",
+        post(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }),
+    );
+    let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+    let live = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+    // nothing listens there anymore
+    let dead = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/hook", listener.local_addr().unwrap())
+    };
+
+    for url in [dead, live] {
+        subscribe(&writer, url, &[Event::VideoCreated])
+            .await
+            .unwrap();
+    }
+    for id in 1..=3 {
+        writer
+            .run(move |conn| {
+                Box::pin(async move {
+                    enqueue(
+                        conn,
+                        Event::VideoCreated,
+                        &serde_json::json!({ "id": id }),
+                        0,
+                    )
+                    .await
+                })
+            })
+            .await
+            .unwrap();
+    }
+
+    // the dead url is tried once, the live one gets its 3
+    assert_eq!(deliver_due(&pool, &writer, 0).await.unwrap(), 4);
+    assert_eq!(received.load(Ordering::SeqCst), 3);
+
+    let statuses = |subscription_id| {
+        let pool = pool.clone();
+        async move {
+            db::get_webhook_deliveries(&pool, subscription_id, None, 10)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|d| (d.status, d.attempts))
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(
+        statuses(1).await,
+        vec![
+            ("pending".to_string(), 0),
+            ("pending".to_string(), 0),
+            ("pending".to_string(), 1)
+        ]
+    );
+    assert_eq!(statuses(2).await, vec![("delivered".to_string(), 1); 3]);
+
+    let _ = std::fs::remove_file(&path);
+}
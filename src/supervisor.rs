@@ -0,0 +1,350 @@
+//! Keeps the background tasks running: a task that panics (or returns) is restarted with
+//! backoff, and each task reports how its passes go. The HTTP layer reads the state back.
+//!
+//! A task can be paused: it waits before its next pass until it's resumed.
+//!
+//! On shutdown the tasks are asked to stop: they finish their pass, or leave it at a safe
+//! point, and the ones still running after the timeout are aborted.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::{task, time};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct TaskState {
+    pub running: bool,
+    pub paused: bool,
+    /// unix time in ms of the last (re)start
+    pub started_at: Option<i64>,
+    pub restarts: u32,
+    pub last_success_at: Option<i64>,
+    pub last_failure_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+type Tasks = Arc<Mutex<BTreeMap<&'static str, TaskState>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Shutdown {
+    No,
+    /// the tasks should stop at their next safe point
+    Stop,
+    /// the tasks took too long, they're aborted wherever they are
+    Abort,
+}
+
+/// Resolves once the shutdown reaches `at`.
+async fn reached(shutdown: &mut watch::Receiver<Shutdown>, at: Shutdown) {
+    while *shutdown.borrow() < at {
+        if shutdown.changed().await.is_err() {
+            // the supervisor is gone, nobody will ask
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Given to a task to report the outcome of each pass, and to know when to stop.
+#[derive(Clone)]
+pub struct TaskReporter {
+    name: &'static str,
+    tasks: Tasks,
+    shutdown: watch::Receiver<Shutdown>,
+    paused: watch::Receiver<bool>,
+}
+
+impl TaskReporter {
+    pub fn success(&self) {
+        self.update(|state| state.last_success_at = Some(crate::unix_time_in_ms()));
+    }
+
+    pub fn failure(&self, error: impl Display) {
+        let error = error.to_string();
+        self.update(|state| {
+            state.last_failure_at = Some(crate::unix_time_in_ms());
+            state.last_error = Some(error);
+        });
+    }
+
+    /// Whether the process is shutting down, the task should return at its next safe point.
+    pub fn stopping(&self) -> bool {
+        *self.shutdown.borrow() >= Shutdown::Stop
+    }
+
+    /// Resolves when the process starts shutting down, to `select!` with the task's wait for
+    /// its next pass.
+    pub async fn stopped(&self) {
+        reached(&mut self.shutdown.clone(), Shutdown::Stop).await
+    }
+
+    /// Waits while the task is paused, to call before each pass. Returns false if the process
+    /// is shutting down instead.
+    pub async fn unpaused(&self) -> bool {
+        let mut paused = self.paused.clone();
+
+        while *paused.borrow() && !self.stopping() {
+            tokio::select! {
+                changed = paused.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = self.stopped() => {}
+            }
+        }
+
+        !self.stopping()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut TaskState)) {
+        if let Some(state) = self.tasks.lock().unwrap().get_mut(self.name) {
+            update(state);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+    tasks: Tasks,
+    shutdown: Arc<watch::Sender<Shutdown>>,
+    monitors: Arc<Mutex<Vec<task::JoinHandle<()>>>>,
+    paused: Arc<Mutex<BTreeMap<&'static str, watch::Sender<bool>>>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor {
+            tasks: Tasks::default(),
+            shutdown: Arc::new(watch::channel(Shutdown::No).0),
+            monitors: Arc::default(),
+            paused: Arc::default(),
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    /// Runs the future made by `make_task` until it panics or returns, then makes a new one after
+    /// a backoff: 1s doubling up to 5 minutes, back to 1s once a run outlasts the backoff.
+    pub fn spawn<F, Fut>(&self, name: &'static str, make_task: F)
+    where
+        F: Fn(TaskReporter) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        let (pause, paused) = watch::channel(false);
+        self.paused.lock().unwrap().insert(name, pause);
+        let reporter = TaskReporter {
+            name,
+            tasks: self.tasks.clone(),
+            shutdown: shutdown.clone(),
+            paused,
+        };
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(name, TaskState::default());
+
+        let monitor = task::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+
+            while !reporter.stopping() {
+                reporter.update(|state| {
+                    state.running = true;
+                    state.started_at = Some(crate::unix_time_in_ms());
+                });
+                let started = time::Instant::now();
+
+                let mut run = task::spawn(make_task(reporter.clone()));
+                let result = tokio::select! {
+                    result = &mut run => result,
+                    _ = reached(&mut shutdown, Shutdown::Abort) => {
+                        run.abort();
+                        run.await
+                    }
+                };
+
+                let error = match result {
+                    Ok(()) => "stopped".to_string(),
+                    Err(e) if e.is_panic() => {
+                        format!("panicked: {}", panic_message(e.into_panic()))
+                    }
+                    Err(e) => e.to_string(),
+                };
+
+                if reporter.stopping() {
+                    log::info!(target: "supervisor", "{} {}", name, error);
+                    reporter.update(|state| state.running = false);
+                    break;
+                }
+
+                if started.elapsed() > backoff {
+                    backoff = MIN_BACKOFF;
+                }
+
+                log::error!(target: "supervisor", "{} {}, restarting in {:?}", name, error, backoff);
+                reporter.failure(&error);
+                reporter.update(|state| {
+                    state.running = false;
+                    state.restarts += 1;
+                });
+
+                tokio::select! {
+                    _ = time::sleep(backoff) => {}
+                    _ = reporter.stopped() => break,
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+
+        self.monitors.lock().unwrap().push(monitor);
+    }
+
+    /// Asks the tasks to stop and waits for them, aborting those still running after
+    /// `timeout`. The tasks aren't restarted anymore.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let _ = self.shutdown.send(Shutdown::Stop);
+
+        let monitors = std::mem::take(&mut *self.monitors.lock().unwrap());
+        let monitors = futures::future::join_all(monitors);
+        tokio::pin!(monitors);
+
+        if time::timeout(timeout, &mut monitors).await.is_err() {
+            let running: Vec<&str> = self
+                .states()
+                .into_iter()
+                .filter(|(_, state)| state.running)
+                .map(|(name, _)| name)
+                .collect();
+            log::warn!(target: "supervisor",
+                "{} still running after {:?}, aborting",
+                running.join(", "),
+                timeout
+            );
+
+            let _ = self.shutdown.send(Shutdown::Abort);
+            monitors.await;
+        }
+    }
+
+    /// Pauses (or resumes) the task at its next pass. Returns false if there's no such task.
+    pub fn set_paused(&self, name: &str, paused: bool) -> bool {
+        let sent = match self.paused.lock().unwrap().get(name) {
+            Some(pause) => pause.send(paused).is_ok(),
+            None => return false,
+        };
+
+        if let Some(state) = self.tasks.lock().unwrap().get_mut(name) {
+            state.paused = paused;
+        }
+
+        sent
+    }
+
+    pub fn states(&self) -> BTreeMap<&'static str, TaskState> {
+        self.tasks.lock().unwrap().clone()
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[tokio::test]
+async fn restart_panicked_tasks() {
+    let supervisor = Supervisor::new();
+    let runs = Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+    let runs_for_task = runs.clone();
+    supervisor.spawn("flaky", move |reporter| {
+        let runs = runs_for_task.clone();
+        async move {
+            if runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                panic!("first run fails");
+            }
+            reporter.success();
+            std::future::pending::<()>().await;
+        }
+    });
+
+    time::sleep(MIN_BACKOFF + Duration::from_millis(500)).await;
+
+    let state = &supervisor.states()["flaky"];
+    assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert!(state.running);
+    assert_eq!(state.restarts, 1);
+    assert_eq!(
+        state.last_error.as_deref(),
+        Some("panicked: first run fails")
+    );
+    assert!(state.last_success_at.is_some());
+}
+
+#[tokio::test]
+async fn stop_tasks_on_shutdown() {
+    let supervisor = Supervisor::new();
+
+    supervisor.spawn("polite", |reporter| async move {
+        reporter.stopped().await;
+        reporter.success();
+    });
+    supervisor.spawn("stubborn", |_| std::future::pending::<()>());
+
+    time::sleep(Duration::from_millis(100)).await;
+    supervisor.shutdown(Duration::from_millis(200)).await;
+
+    let states = supervisor.states();
+    assert!(!states["polite"].running);
+    assert!(states["polite"].last_success_at.is_some());
+    assert!(!states["stubborn"].running);
+    assert_eq!(states["stubborn"].restarts, 0);
+}
+
+#[tokio::test]
+async fn pause_and_resume() {
+    let supervisor = Supervisor::new();
+    let passes = Arc::new(std::sync::atomic::AtomicU32::new(0));
+
+    let passes_for_task = passes.clone();
+    supervisor.spawn("ticking", move |reporter| {
+        let passes = passes_for_task.clone();
+        async move {
+            while reporter.unpaused().await {
+                passes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    });
+
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(supervisor.set_paused("ticking", true));
+    assert!(supervisor.states()["ticking"].paused);
+    time::sleep(Duration::from_millis(20)).await;
+
+    let paused_at = passes.load(std::sync::atomic::Ordering::SeqCst);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(passes.load(std::sync::atomic::Ordering::SeqCst), paused_at);
+
+    assert!(supervisor.set_paused("ticking", false));
+    time::sleep(Duration::from_millis(50)).await;
+    assert!(passes.load(std::sync::atomic::Ordering::SeqCst) > paused_at);
+
+    assert!(!supervisor.set_paused("missing", true));
+    supervisor.shutdown(Duration::from_millis(100)).await;
+}
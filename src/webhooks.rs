@@ -0,0 +1,190 @@
+use axum::{
+    extract::{Extension, Query},
+    response::Json,
+    routing::post,
+    Router,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::{
+    sync::{Mutex, Notify},
+    task,
+};
+
+use crate::cache::VideoCache;
+use crate::subscriptions::{self, Event};
+use crate::writer::Writer;
+use crate::{brightcove, db};
+
+/// Brightcove can't add headers to its notifications, so the shared secret travels in the
+/// callback url: `https://.../webhooks/brightcove?secret=...`.
+#[derive(Clone)]
+struct WebhookConfig {
+    secret: Option<Arc<String>>,
+    video_sync: Arc<Notify>,
+    /// to ask the CMS API about the videos the Playback API doesn't have anymore
+    brightcove_access_token: Arc<Mutex<String>>,
+    pool: SqlitePool,
+    writer: Writer,
+    video_cache: Arc<VideoCache>,
+}
+
+/// Either a CMS API notification (`video`) or a Dynamic Ingest callback (`videoId`), only the
+/// fields used here are kept.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Notification {
+    pub event: Option<String>,
+    pub action: Option<String>,
+    pub status: Option<String>,
+    #[serde(alias = "videoId")]
+    pub video: Option<String>,
+}
+
+/// Routes receiving Brightcove notifications. `video_sync` is notified to run the video sync
+/// right away instead of waiting for its interval, and a `video-change` of a video already in
+/// the db refreshes it (or makes it unavailable).
+///
+/// `BRIGHTCOVE_WEBHOOK_SECRET` has to be set, otherwise every notification is refused.
+pub fn router(
+    video_sync: Arc<Notify>,
+    brightcove_access_token: Arc<Mutex<String>>,
+    pool: SqlitePool,
+    writer: Writer,
+    video_cache: Arc<VideoCache>,
+) -> Router {
+    let secret = std::env::var("BRIGHTCOVE_WEBHOOK_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(Arc::new);
+
+    if secret.is_none() {
+        log::info!("BRIGHTCOVE_WEBHOOK_SECRET not set, brightcove notifications disabled");
+    }
+
+    Router::new()
+        .route("/brightcove", post(brightcove_notification))
+        .layer(Extension(WebhookConfig {
+            secret,
+            video_sync,
+            brightcove_access_token,
+            pool,
+            writer,
+            video_cache,
+        }))
+}
+
+async fn brightcove_notification(
+    config: Extension<WebhookConfig>,
+    params: Query<HashMap<String, String>>,
+    Json(notification): Json<Notification>,
+) -> StatusCode {
+    let secret = match &config.secret {
+        Some(secret) => secret,
+        None => return StatusCode::NOT_IMPLEMENTED,
+    };
+
+    let valid = match params.get("secret") {
+        Some(given) => {
+            given.len() == secret.len() && openssl::memcmp::eq(given.as_bytes(), secret.as_bytes())
+        }
+        None => false,
+    };
+
+    if !valid {
+        log::info!(target: "webhooks", "refused brightcove notification, bad secret");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    log::info!(target: "webhooks", "brightcove notification: {:?}", notification);
+
+    // a failed ingest won't have anything new to sync
+    if notification.status.as_deref() != Some("FAILED") {
+        config.video_sync.notify_one();
+    }
+
+    if let (Some("video-change"), Some(video_id)) =
+        (notification.event.as_deref(), notification.video)
+    {
+        let config = config.0.clone();
+        task::spawn(async move {
+            if let Err(e) = refresh_video(&config, video_id.clone()).await {
+                log::error!(target: "webhooks", "fail refreshing {}: {}", video_id, e);
+            }
+        });
+    }
+
+    StatusCode::NO_CONTENT
+}
+
+/// Brings a video already in the db up to date with Brightcove, new videos are left to the
+/// video sync. A video the Playback API doesn't have anymore is only made unavailable if the
+/// CMS API confirms it's deleted, inactive or out of its schedule, its rows are kept.
+async fn refresh_video(config: &WebhookConfig, video_id: String) -> anyhow::Result<()> {
+    let video = brightcove::get_video(&video_id).await?;
+    let now = crate::unix_time_in_ms();
+
+    match video {
+        Some(video) => {
+            let row: db::VideoRow = (&video).into();
+            if config
+                .writer
+                .run(move |conn| Box::pin(async move { db::update_video(conn, &row).await }))
+                .await?
+            {
+                config.video_cache.invalidate_videos([video_id.as_str()]);
+                // the listings show the video too
+                config.video_cache.invalidate_lists();
+                if let Some(video) = db::get_video(&config.pool, &video_id).await? {
+                    config
+                        .writer
+                        .run(move |conn| {
+                            Box::pin(async move {
+                                subscriptions::enqueue(conn, Event::VideoUpdated, &video, now).await
+                            })
+                        })
+                        .await?;
+                }
+                log::info!(target: "webhooks", "refreshed video {}", video_id);
+            }
+        }
+        // deleted, deactivated, out of its schedule or geo restricted for the server
+        None => {
+            let token = config.brightcove_access_token.lock().await.clone();
+            let cms_video = brightcove::get_cms_video(&token, &video_id).await?;
+            let event = match crate::unavailable_event(cms_video.as_ref(), now) {
+                Some(event) => event,
+                None => {
+                    log::info!(target: "webhooks", "video {} still playable, not from here", video_id);
+                    return Ok(());
+                }
+            };
+
+            if crate::make_unavailable(&config.writer, video_id.clone(), event, now).await? {
+                config.video_cache.invalidate_videos([video_id.as_str()]);
+                config.video_cache.invalidate_lists();
+                log::info!(target: "webhooks", "made video {} unavailable, {}", video_id, event.as_str());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn deserialize_notifications() {
+    let cms: Notification = serde_json::from_str(
+        r#"{"timestamp":1648000000000,"account_id":"1","event":"video-change","video":"6301819598001","version":26}"#,
+    )
+    .unwrap();
+    assert_eq!(cms.video.as_deref(), Some("6301819598001"));
+
+    let ingest: Notification = serde_json::from_str(
+        r#"{"entity":"6301819598001","entityType":"TITLE","version":"1","action":"CREATE","status":"SUCCESS","videoId":"6301819598001","jobId":"x"}"#,
+    )
+    .unwrap();
+    assert_eq!(ingest.video.as_deref(), Some("6301819598001"));
+    assert_eq!(ingest.status.as_deref(), Some("SUCCESS"));
+}
@@ -0,0 +1,182 @@
+//! The only connection writing to the db, owned by a task the syncs send their writes to.
+//!
+//! SQLite allows one writer at a time: funnelling the writes through one connection, instead
+//! of having the tasks take turns on a shared pool, keeps them from waiting on each other's
+//! HTTP calls and from failing with `SQLITE_BUSY`. Reads go to the read pool, which WAL lets
+//! run alongside the writes.
+
+use futures::future::{BoxFuture, FutureExt};
+use sqlx::{Connection, SqliteConnection};
+use std::panic::AssertUnwindSafe;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+/// Batches waiting for the writer before `run` waits for room.
+const QUEUE_SIZE: usize = 64;
+
+type Job = Box<dyn for<'c> FnOnce(&'c mut SqliteConnection) -> BoxFuture<'c, ()> + Send>;
+
+/// Handle to the writer task, cheap to clone.
+#[derive(Clone)]
+pub struct Writer {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Writer {
+    /// Spawns the writer task owning `conn`. It runs until every `Writer` is dropped, then
+    /// closes the connection: the returned handle resolves once the queued batches are done
+    /// and the connection is closed.
+    pub fn spawn(mut conn: SqliteConnection) -> (Self, task::JoinHandle<()>) {
+        let (jobs, mut queue) = mpsc::channel::<Job>(QUEUE_SIZE);
+
+        let handle = task::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                // a batch panicking fails alone, its caller gets an error and the writer goes
+                // on. a transaction left open is rolled back by the next batch
+                if AssertUnwindSafe(job(&mut conn))
+                    .catch_unwind()
+                    .await
+                    .is_err()
+                {
+                    log::error!(target: "writer", "a batch panicked");
+                }
+            }
+
+            if let Err(e) = conn.close().await {
+                log::error!(target: "writer", "fail closing the connection: {}", e);
+            }
+        });
+
+        (Writer { jobs }, handle)
+    }
+
+    /// Runs a batch of writes on the writer connection, after the batches queued before it.
+    ///
+    /// ```ignore
+    /// writer.run(move |conn| Box::pin(async move { db::save_videos(conn, &videos).await }))
+    /// ```
+    pub async fn run<T, F>(&self, batch: F) -> anyhow::Result<T>
+    where
+        F: for<'c> FnOnce(&'c mut SqliteConnection) -> BoxFuture<'c, anyhow::Result<T>>
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+
+        let job: Job = Box::new(move |conn| {
+            Box::pin(async move {
+                // the caller may be gone, the batch is done all the same
+                let _ = result_tx.send(batch(conn).await);
+            })
+        });
+
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| anyhow::anyhow!("the db writer has stopped"))?;
+
+        match result_rx.await {
+            Ok(result) => result,
+            Err(_) if self.is_running() => anyhow::bail!("the db batch panicked"),
+            Err(_) => anyhow::bail!("the db writer has stopped"),
+        }
+    }
+
+    /// Whether the writer task still takes batches.
+    pub fn is_running(&self) -> bool {
+        !self.jobs.is_closed()
+    }
+}
+
+#[tokio::test]
+async fn run_batches_on_one_connection() {
+    use sqlx::ConnectOptions;
+    use std::str::FromStr;
+
+    let conn = sqlx::sqlite::SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let (writer, closed) = Writer::spawn(conn);
+
+    writer
+        .run(|conn| {
+            Box::pin(async move {
+                sqlx::query("CREATE TABLE t (n INTEGER)")
+                    .execute(conn)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await
+        .unwrap();
+
+    let inserts: Vec<_> = (1..=3)
+        .map(|n| {
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                writer
+                    .run(move |conn| {
+                        Box::pin(async move {
+                            sqlx::query("INSERT INTO t (n) VALUES (?)")
+                                .bind(n)
+                                .execute(conn)
+                                .await?;
+                            Ok(())
+                        })
+                    })
+                    .await
+            })
+        })
+        .collect();
+    for insert in inserts {
+        insert.await.unwrap().unwrap();
+    }
+
+    let (sum,): (i64,) = writer
+        .run(|conn| {
+            Box::pin(async move {
+                Ok(sqlx::query_as("SELECT SUM(n) FROM t")
+                    .fetch_one(conn)
+                    .await?)
+            })
+        })
+        .await
+        .unwrap();
+    assert_eq!(sum, 6);
+
+    let failed: anyhow::Result<()> = writer
+        .run(|conn| {
+            Box::pin(async move {
+                sqlx::query("INSERT INTO missing VALUES (1)")
+                    .execute(conn)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await;
+    assert!(failed.is_err());
+
+    let panicked: anyhow::Result<()> = writer
+        .run(|_| Box::pin(async move { panic!("bad batch") }))
+        .await;
+    assert_eq!(panicked.unwrap_err().to_string(), "the db batch panicked");
+    assert!(writer.is_running());
+
+    let (count,): (i64,) = writer
+        .run(|conn| {
+            Box::pin(async move {
+                Ok(sqlx::query_as("SELECT COUNT(*) FROM t")
+                    .fetch_one(conn)
+                    .await?)
+            })
+        })
+        .await
+        .unwrap();
+    assert_eq!(count, 3);
+
+    drop(writer);
+    closed.await.unwrap();
+}